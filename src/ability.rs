@@ -1,6 +1,7 @@
-use crate::dimensions::WorldPoint as Point;
-use crate::game::{BuildingType, GameDur, State, UnitTraining, UnitType, UID};
-use crate::map::{TilePoint, ToTilePoint, TILE_WIDTH};
+use crate::dimensions::{WorldCoord as Coord, WorldPoint as Point};
+use crate::entity::UID;
+use crate::game::{BuildingType, GameDur, Order, State, UnitTraining, UnitType};
+use crate::map::{TilePoint, ToTilePoint, TILE_WIDTH, TILE_WIDTH_COORD};
 
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
@@ -8,6 +9,7 @@ use sdl2::rect::Rect as SdlRect;
 use sdl2::render::{BlendMode, Canvas};
 use sdl2::video::Window;
 
+use std::fmt;
 use std::rc::Rc;
 
 #[derive(Clone)]
@@ -23,6 +25,18 @@ pub trait AbilityCommon {
   // TODO: When units can die we should use this to stop trying to cast
   // active abilities from the dead unit.
   fn caster(&self) -> UID;
+
+  // How long the caster has to wait after casting before it can cast again.
+  fn cooldown(&self) -> GameDur {
+    GameDur { ticks: 0 }
+  }
+  // How much energy each cast drains from the caster's energy pool.
+  fn energy_cost(&self) -> u32 {
+    0
+  }
+  fn multi_cast(&self) -> MultiCast {
+    MultiCast::All
+  }
 }
 
 impl AbilityCommon for Ability {
@@ -46,19 +60,204 @@ impl AbilityCommon for Ability {
       Ability::PointTargeted(ab) => ab.caster(),
     }
   }
+
+  fn cooldown(&self) -> GameDur {
+    match self {
+      Ability::NonTargeted(ab) => ab.cooldown(),
+      Ability::PointTargeted(ab) => ab.cooldown(),
+    }
+  }
+
+  fn energy_cost(&self) -> u32 {
+    match self {
+      Ability::NonTargeted(ab) => ab.energy_cost(),
+      Ability::PointTargeted(ab) => ab.energy_cost(),
+    }
+  }
+
+  fn multi_cast(&self) -> MultiCast {
+    match self {
+      Ability::NonTargeted(ab) => ab.multi_cast(),
+      Ability::PointTargeted(ab) => ab.multi_cast(),
+    }
+  }
+}
+
+// How an ability is cast when several selected units or buildings have it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MultiCast {
+  // Every selected caster casts it, e.g. a stop command.
+  All,
+  // Only the least busy caster casts it, e.g. training a unit. Ties go to the
+  // caster closest to the target, if there is one.
+  One,
+}
+
+// Reasons that casting an ability can fail.
+#[derive(Clone, Copy, Debug)]
+pub enum CastError {
+  CasterNotFound,
+  OnCooldown(GameDur),
+  NotEnoughEnergy { needed: u32, available: u32 },
+  TrainQueueFull,
+  UnderConstruction,
+  // Part of where the building would go is blocked, or off the map.
+  SiteBlocked,
+  // The builder has no way to get to where the building would go.
+  SiteUnreachable,
+}
+
+impl fmt::Display for CastError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CastError::CasterNotFound => write!(f, "caster not found"),
+      CastError::OnCooldown(left) => write!(f, "on cooldown for {}s", left.secs_ceil()),
+      CastError::NotEnoughEnergy { needed, available } => {
+        write!(f, "needs {} energy but only has {}", needed, available)
+      }
+      CastError::TrainQueueFull => write!(f, "train queue is full"),
+      CastError::UnderConstruction => write!(f, "caster is still under construction"),
+      CastError::SiteBlocked => write!(f, "can't build there"),
+      CastError::SiteUnreachable => write!(f, "can't get there to build"),
+    }
+  }
 }
 
 pub trait NonTargetedAbility: AbilityCommon {
-  fn cast(&self, state: &mut State);
+  // Does whatever the ability does. Call cast() instead, which also checks
+  // and applies the cooldown and energy cost.
+  fn apply(&self, state: &mut State) -> Result<(), CastError>;
+
+  fn cast(&self, state: &mut State) -> Result<(), CastError> {
+    state.check_can_cast(self.caster(), self.name(), self.energy_cost())?;
+    self.apply(state)?;
+    state.pay_for_cast(
+      self.caster(),
+      self.name(),
+      self.cooldown(),
+      self.energy_cost(),
+    );
+    Ok(())
+  }
 }
 
 pub trait PointTargetedAbility: AbilityCommon {
-  fn cast(&self, state: &mut State, target: Point);
+  // Does whatever the ability does. Call cast() instead, which also checks
+  // and applies the cooldown and energy cost.
+  fn apply(&self, state: &mut State, target: Point) -> Result<(), CastError>;
+
+  // How close a unit has to get to the target before casting. None means the
+  // ability can be cast from anywhere.
+  fn range(&self) -> Option<Coord> {
+    None
+  }
+
+  fn cast(&self, state: &mut State, target: Point) -> Result<(), CastError> {
+    state.check_can_cast(self.caster(), self.name(), self.energy_cost())?;
+    self.apply(state, target)?;
+    state.pay_for_cast(
+      self.caster(),
+      self.name(),
+      self.cooldown(),
+      self.energy_cost(),
+    );
+    Ok(())
+  }
 
   // Draw anything you want to while the ability is selected.
   fn draw(&self, canvas: &mut Canvas<Window>, mouse: Point, camera: Point);
 }
 
+// Casts a non-targeted ability that several selected casters share. Returns
+// an error only if none of the casters could cast it.
+pub fn cast_non_targeted_group(
+  state: &mut State,
+  abilities: &[Rc<dyn NonTargetedAbility>],
+) -> Result<(), CastError> {
+  let order = cast_order(state, abilities, None);
+  cast_in_order(order, |ab| abilities[ab].cast(state))
+}
+
+// Orders a point-targeted ability that several selected casters share to be
+// cast at the target. If queue is true, units cast it after finishing their
+// other orders. Returns an error only if none of the casters could cast it.
+pub fn cast_point_targeted_group(
+  state: &mut State,
+  abilities: &[Rc<dyn PointTargetedAbility>],
+  target: Point,
+  queue: bool,
+) -> Result<(), CastError> {
+  let order = cast_order(state, abilities, Some(target));
+  cast_in_order(order, |ab| {
+    state.order_cast(abilities[ab].clone(), target, queue)
+  })
+}
+
+// Which abilities to try casting, as indices into `abilities`. For
+// MultiCast::One abilities they're sorted best caster first, and the caller
+// should stop after the first successful cast.
+fn cast_order<A: AbilityCommon + ?Sized>(
+  state: &State,
+  abilities: &[Rc<A>],
+  target: Option<Point>,
+) -> CastOrder {
+  let mut order: Vec<usize> = (0..abilities.len()).collect();
+  let multi_cast = match abilities.first() {
+    Some(ab) => ab.multi_cast(),
+    None => return CastOrder::All(order),
+  };
+  if multi_cast == MultiCast::All {
+    return CastOrder::All(order);
+  }
+
+  let priority = |i: &usize| {
+    let caster = abilities[*i].caster();
+    let busyness = state.busyness(caster).unwrap_or(u32::MAX);
+    let distance = match (target, state.entity_pos(caster)) {
+      (Some(target), Some(pos)) => (target - pos).magnitude(),
+      _ => Coord::ZERO,
+    };
+    (busyness, distance)
+  };
+  order.sort_by(|a, b| {
+    priority(a)
+      .partial_cmp(&priority(b))
+      .unwrap_or(std::cmp::Ordering::Equal)
+  });
+  CastOrder::FirstSuccess(order)
+}
+
+enum CastOrder {
+  All(Vec<usize>),
+  FirstSuccess(Vec<usize>),
+}
+
+fn cast_in_order(
+  order: CastOrder,
+  mut cast: impl FnMut(usize) -> Result<(), CastError>,
+) -> Result<(), CastError> {
+  let mut result = Err(CastError::CasterNotFound);
+  match order {
+    CastOrder::All(order) => {
+      for i in order {
+        let cast_result = cast(i);
+        if result.is_err() {
+          result = cast_result;
+        }
+      }
+    }
+    CastOrder::FirstSuccess(order) => {
+      for i in order {
+        result = cast(i);
+        if result.is_ok() {
+          break;
+        }
+      }
+    }
+  }
+  result
+}
+
 // An ability for worker units: build a building at the target location.
 pub struct AbilityBuild {
   caster: UID,
@@ -92,12 +291,47 @@ impl AbilityCommon for AbilityBuild {
   fn caster(&self) -> UID {
     self.caster
   }
+  fn multi_cast(&self) -> MultiCast {
+    MultiCast::One
+  }
 }
 
 const BUILD_GHOST_COLOR: Color = Color::RGBA(139, 233, 253, 128);
 impl PointTargetedAbility for AbilityBuild {
-  fn cast(&self, state: &mut State, target: Point) {
-    state.make_building(self.building_type.clone(), self.where_to_build(target));
+  fn apply(&self, state: &mut State, target: Point) -> Result<(), CastError> {
+    let owner = state.owner(self.caster).ok_or(CastError::CasterNotFound)?;
+    let builder_pos = state
+      .unit(self.caster)
+      .ok_or(CastError::CasterNotFound)?
+      .pos
+      .to_tile_point();
+    // Every tile under the building has to be free, without buildings or
+    // resources on it, and somewhere the builder can walk to.
+    let top_left = self.where_to_build(target);
+    for y in 0..self.building_type.height {
+      for x in 0..self.building_type.width {
+        let p = top_left + TilePoint::new(x, y);
+        let on_resources = state.resource_nodes.iter().any(|node| node.pos == p);
+        if !state.map.is_walkable(p) || on_resources {
+          return Err(CastError::SiteBlocked);
+        }
+        if !state.map.is_reachable(builder_pos, p) {
+          return Err(CastError::SiteUnreachable);
+        }
+      }
+    }
+    let site = state.start_construction(self.building_type.clone(), top_left, owner);
+    // The builder gets straight to work on the new site.
+    if let Some(builder) = state.get_unit(self.caster) {
+      builder.interrupt(Order::Build(site));
+    }
+    Ok(())
+  }
+
+  fn range(&self) -> Option<Coord> {
+    // Close enough to touch the edge of the building.
+    let dim = u32::max(self.building_type.width, self.building_type.height) as i64;
+    Some(Coord::from_int(dim + 2) * TILE_WIDTH_COORD / Coord::from_int(2))
   }
 
   fn draw(&self, canvas: &mut Canvas<Window>, mouse: Point, camera: Point) {
@@ -135,24 +369,228 @@ impl AbilityCommon for AbilityTrain {
   fn name(&self) -> &'static str {
     "Train unit"
   }
+
+  fn multi_cast(&self) -> MultiCast {
+    MultiCast::One
+  }
 }
 
 impl NonTargetedAbility for AbilityTrain {
-  fn cast(&self, state: &mut State) {
+  fn apply(&self, state: &mut State) -> Result<(), CastError> {
     let unit_type = state.unit_types[0].clone();
     let building = state
       .get_building(self.caster)
-      // TODO: Make cast() give a result
-      .expect("caster not found when casting ability");
+      .ok_or(CastError::CasterNotFound)?;
     let train_dur = GameDur::from_secs(3);
-    if building.train_queue_max_len > building.train_queue.len() {
-      building.train_queue.push_back(UnitTraining {
-        unit_type,
-        dur_total: train_dur,
-        dur_left: train_dur,
-      });
-    } else {
-      // TODO: Signal to the user that casting failed.
+    if building.train_queue_max_len <= building.train_queue.len() {
+      return Err(CastError::TrainQueueFull);
     }
+    building.train_queue.push_back(UnitTraining {
+      unit_type,
+      dur_total: train_dur,
+      dur_left: train_dur,
+    });
+    Ok(())
+  }
+}
+
+// Heal: the caster patches itself up a bit. Mostly there to have something
+// with a cooldown and an energy cost.
+pub struct AbilityHeal {
+  caster: UID,
+}
+
+const HEAL_AMOUNT: u32 = 15;
+
+impl AbilityHeal {
+  pub fn new(caster: UID) -> Ability {
+    Ability::NonTargeted(Rc::new(AbilityHeal { caster }))
+  }
+}
+
+impl AbilityCommon for AbilityHeal {
+  fn keycode(&self) -> Keycode {
+    Keycode::R
+  }
+  fn name(&self) -> &'static str {
+    "Heal"
+  }
+  fn caster(&self) -> UID {
+    self.caster
+  }
+  fn cooldown(&self) -> GameDur {
+    GameDur::from_secs(10)
+  }
+  fn energy_cost(&self) -> u32 {
+    50
+  }
+}
+
+impl NonTargetedAbility for AbilityHeal {
+  fn apply(&self, state: &mut State) -> Result<(), CastError> {
+    let unit = state
+      .get_unit(self.caster)
+      .ok_or(CastError::CasterNotFound)?;
+    unit.hp = u32::min(unit.hp + HEAL_AMOUNT, unit.unit_type.max_hp);
+    Ok(())
+  }
+}
+
+// Commands that every mobile unit has. They're abilities so that they get
+// hotkeys and show up in the ability list, but all they do is give the caster
+// an order.
+
+// Stop: drop all orders.
+pub struct AbilityStop {
+  caster: UID,
+}
+
+impl AbilityStop {
+  pub fn new(caster: UID) -> Ability {
+    Ability::NonTargeted(Rc::new(AbilityStop { caster }))
+  }
+}
+
+impl AbilityCommon for AbilityStop {
+  fn keycode(&self) -> Keycode {
+    Keycode::S
+  }
+  fn name(&self) -> &'static str {
+    "Stop"
+  }
+  fn caster(&self) -> UID {
+    self.caster
+  }
+}
+
+impl NonTargetedAbility for AbilityStop {
+  fn apply(&self, state: &mut State) -> Result<(), CastError> {
+    let unit = state
+      .get_unit(self.caster)
+      .ok_or(CastError::CasterNotFound)?;
+    unit.interrupt(Order::Stop);
+    Ok(())
+  }
+}
+
+// Hold position: stay put and attack anything that comes into range.
+pub struct AbilityHold {
+  caster: UID,
+}
+
+impl AbilityHold {
+  pub fn new(caster: UID) -> Ability {
+    Ability::NonTargeted(Rc::new(AbilityHold { caster }))
+  }
+}
+
+impl AbilityCommon for AbilityHold {
+  fn keycode(&self) -> Keycode {
+    Keycode::H
+  }
+  fn name(&self) -> &'static str {
+    "Hold position"
+  }
+  fn caster(&self) -> UID {
+    self.caster
+  }
+}
+
+impl NonTargetedAbility for AbilityHold {
+  fn apply(&self, state: &mut State) -> Result<(), CastError> {
+    let unit = state
+      .get_unit(self.caster)
+      .ok_or(CastError::CasterNotFound)?;
+    unit.clear_orders();
+    unit.queue_order(Order::HoldPosition);
+    Ok(())
+  }
+}
+
+const COMMAND_TARGET_COLOR: Color = Color::RGB(255, 85, 85);
+const COMMAND_TARGET_RAD: u32 = 4;
+
+fn draw_command_target(canvas: &mut Canvas<Window>, mouse: Point, camera: Point) {
+  canvas.set_draw_color(COMMAND_TARGET_COLOR);
+  let _ = canvas.draw_rect(SdlRect::from_center(
+    mouse.to_window(camera),
+    COMMAND_TARGET_RAD * 2,
+    COMMAND_TARGET_RAD * 2,
+  ));
+}
+
+// Patrol: walk back and forth between where the unit is when it starts
+// patrolling and the target, attacking enemies on the way.
+pub struct AbilityPatrol {
+  caster: UID,
+}
+
+impl AbilityPatrol {
+  pub fn new(caster: UID) -> Ability {
+    Ability::PointTargeted(Rc::new(AbilityPatrol { caster }))
+  }
+}
+
+impl AbilityCommon for AbilityPatrol {
+  fn keycode(&self) -> Keycode {
+    Keycode::P
+  }
+  fn name(&self) -> &'static str {
+    "Patrol"
+  }
+  fn caster(&self) -> UID {
+    self.caster
+  }
+}
+
+impl PointTargetedAbility for AbilityPatrol {
+  fn apply(&self, state: &mut State, target: Point) -> Result<(), CastError> {
+    let unit = state
+      .get_unit(self.caster)
+      .ok_or(CastError::CasterNotFound)?;
+    let from = unit.pos;
+    unit.interrupt(Order::Patrol { from, to: target });
+    Ok(())
+  }
+
+  fn draw(&self, canvas: &mut Canvas<Window>, mouse: Point, camera: Point) {
+    draw_command_target(canvas, mouse, camera);
+  }
+}
+
+// Attack-move: move to the target, attacking enemies on the way.
+pub struct AbilityAttackMove {
+  caster: UID,
+}
+
+impl AbilityAttackMove {
+  pub fn new(caster: UID) -> Ability {
+    Ability::PointTargeted(Rc::new(AbilityAttackMove { caster }))
+  }
+}
+
+impl AbilityCommon for AbilityAttackMove {
+  fn keycode(&self) -> Keycode {
+    Keycode::A
+  }
+  fn name(&self) -> &'static str {
+    "Attack-move"
+  }
+  fn caster(&self) -> UID {
+    self.caster
+  }
+}
+
+impl PointTargetedAbility for AbilityAttackMove {
+  fn apply(&self, state: &mut State, target: Point) -> Result<(), CastError> {
+    let unit = state
+      .get_unit(self.caster)
+      .ok_or(CastError::CasterNotFound)?;
+    unit.interrupt(Order::AttackMove(target));
+    Ok(())
+  }
+
+  fn draw(&self, canvas: &mut Canvas<Window>, mouse: Point, camera: Point) {
+    draw_command_target(canvas, mouse, camera);
   }
 }
@@ -0,0 +1,220 @@
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::dimensions::{WorldCoord as Coord, WorldPoint as Point, WorldRect as Rect};
+use crate::flow_field::FlowField;
+use crate::hpa::PathGraph;
+use crate::map::{GridTile, Map, TilePoint, TILE_WIDTH_F32};
+use crate::spatial::SpatialHash;
+
+// Times pathfinding on a big random map. Run with `rts --bench-pathfinding`,
+// ideally on a release build.
+pub fn pathfinding() {
+  const SIZE: u32 = 512;
+  const QUERIES: usize = 200;
+  const UPDATES: usize = 100;
+
+  // Seeded, so that runs are comparable with each other.
+  let mut rng = StdRng::seed_from_u64(0);
+  let tiles = (0..SIZE * SIZE)
+    .map(|_| {
+      if rng.gen_bool(0.2) {
+        GridTile::Obstacle
+      } else {
+        GridTile::Empty
+      }
+    })
+    .collect();
+  let mut map = Map::new(SIZE, SIZE, tiles);
+  let mut random_open_tile = |map: &Map| loop {
+    let p = TilePoint::new(rng.gen_range(0..SIZE), rng.gen_range(0..SIZE));
+    if map.is_walkable(p) {
+      return p;
+    }
+  };
+
+  println!("pathfinding on a {}x{} map", SIZE, SIZE);
+  let start = Instant::now();
+  let mut graph = PathGraph::new(&map, 1);
+  println!("  building the HPA* graph: {:?}", start.elapsed());
+
+  let queries: Vec<_> = (0..QUERIES)
+    .map(|_| (random_open_tile(&map), random_open_tile(&map)))
+    .collect();
+
+  // A search over the whole map for each path, like every unit used to do.
+  let mut full_search = Duration::from_nanos(0);
+  let mut best_lengths = vec![];
+  for (src, dest) in queries.iter() {
+    let start = Instant::now();
+    let field = FlowField::new(&map, *dest, 1);
+    let path = field.path_from(&map, *src);
+    full_search += start.elapsed();
+    best_lengths.push(path.map(|p| p.len()));
+  }
+  println!(
+    "  full-map search: {:?} per path",
+    full_search / QUERIES as u32
+  );
+
+  let mut hierarchical = Duration::from_nanos(0);
+  let mut extra_steps = 0;
+  let mut optimal_steps = 0;
+  for ((src, dest), best) in queries.iter().zip(best_lengths.iter()) {
+    let start = Instant::now();
+    let path = graph.find_path(&map, *src, *dest);
+    hierarchical += start.elapsed();
+    if let (Some(path), Some(best)) = (path, best) {
+      extra_steps += path.len() - best;
+      optimal_steps += best;
+    }
+  }
+  println!(
+    "  HPA* search: {:?} per path, paths {:.1}% longer than the shortest",
+    hierarchical / QUERIES as u32,
+    100. * extra_steps as f32 / optimal_steps as f32
+  );
+
+  // Placing a building means the map working out its clearance and regions
+  // again, as well as the graph being updated, so both are timed.
+  let mut updating_map = Duration::from_nanos(0);
+  let mut updating_graph = Duration::from_nanos(0);
+  for _ in 0..UPDATES {
+    let p = random_open_tile(&map);
+    let start = Instant::now();
+    map.occupy(p, 3, 3);
+    updating_map += start.elapsed();
+    let start = Instant::now();
+    graph.update(&map, p, 3, 3);
+    updating_graph += start.elapsed();
+  }
+  println!(
+    "  placing a 3x3 building: {:?} each ({:?} updating the map, {:?} the graph)",
+    (updating_map + updating_graph) / UPDATES as u32,
+    updating_map / UPDATES as u32,
+    updating_graph / UPDATES as u32
+  );
+}
+
+// Times finding units near a point, in a box, and the nearest one matching a
+// test, on a big map full of units, by looking through every unit and by using
+// the spatial hash. Run with `rts --bench-spatial`, ideally on a release build.
+pub fn spatial() {
+  const SIZE: u32 = 256;
+  const UNITS: usize = 2000;
+  const QUERIES: usize = 1000;
+  const RAD: Coord = Coord::from_int(8);
+  let range = Coord::from_f32(4. * TILE_WIDTH_F32);
+  let world_width = SIZE as f32 * TILE_WIDTH_F32;
+
+  let mut rng = StdRng::seed_from_u64(0);
+  let map = Map::new(SIZE, SIZE, vec![GridTile::Empty; (SIZE * SIZE) as usize]);
+  let mut random_point = || {
+    Point::new(
+      Coord::from_f32(rng.gen_range(0. ..world_width)),
+      Coord::from_f32(rng.gen_range(0. ..world_width)),
+    )
+  };
+  let mut units: Vec<(usize, Point)> = (0..UNITS).map(|i| (i, random_point())).collect();
+  let queries: Vec<Point> = (0..QUERIES).map(|_| random_point()).collect();
+  let moves: Vec<Point> = (0..UNITS).map(|_| random_point()).collect();
+  let mut index = SpatialHash::new(&map);
+  for (i, pos) in units.iter() {
+    index.insert(*i, *pos, RAD);
+  }
+  // Only every other unit counts for the nearest one, like enemies.
+  let wanted = |i: usize| i.is_multiple_of(2);
+
+  println!("{} units on a {}x{} map", UNITS, SIZE, SIZE);
+  let time = |name: &str, linear: &dyn Fn(Point) -> usize, hashed: &dyn Fn(Point) -> usize| {
+    let (mut linear_time, mut hashed_time) = (Duration::from_nanos(0), Duration::from_nanos(0));
+    for p in queries.iter() {
+      let start = Instant::now();
+      let expected = linear(*p);
+      linear_time += start.elapsed();
+      let start = Instant::now();
+      let found = hashed(*p);
+      hashed_time += start.elapsed();
+      if found != expected {
+        println!(
+          "  {}: the spatial hash found {}, not {}",
+          name, found, expected
+        );
+      }
+    }
+    println!(
+      "  {}: {:?} per query looking through every unit, {:?} with the spatial hash",
+      name,
+      linear_time / QUERIES as u32,
+      hashed_time / QUERIES as u32
+    );
+  };
+
+  time(
+    "units within range",
+    &|p| {
+      units
+        .iter()
+        .filter(|(_, pos)| (*pos - p).magnitude() - RAD <= range)
+        .count()
+    },
+    &|p| index.within(p, range).len(),
+  );
+  time(
+    "units in a box",
+    &|p| {
+      let rect = Rect {
+        top_left: p,
+        width: range,
+        height: range,
+      };
+      units
+        .iter()
+        .filter(|(_, pos)| {
+          rect.top_left.x <= pos.x + RAD
+            && pos.x - RAD <= rect.top_left.x + rect.width
+            && rect.top_left.y <= pos.y + RAD
+            && pos.y - RAD <= rect.top_left.y + rect.height
+        })
+        .count()
+    },
+    &|p| {
+      let rect = Rect {
+        top_left: p,
+        width: range,
+        height: range,
+      };
+      index.in_rect(&rect).len()
+    },
+  );
+  time(
+    "nearest matching unit",
+    &|p| {
+      units
+        .iter()
+        .filter(|(i, _)| wanted(*i))
+        .map(|(i, pos)| (*i, (*pos - p).magnitude() - RAD))
+        .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap_or(Ordering::Equal))
+        .map_or(usize::MAX, |(i, _)| i)
+    },
+    &|p| {
+      index
+        .nearest(p, Coord::from_f32(world_width * 2.), |i, _| wanted(i))
+        .map_or(usize::MAX, |(i, _)| i)
+    },
+  );
+
+  // Keeping the hash up to date costs something every tick too.
+  let start = Instant::now();
+  for ((i, pos), to) in units.iter_mut().zip(moves.iter()) {
+    index.update(*i, *pos, *to);
+    *pos = *to;
+  }
+  println!(
+    "  moving every unit in the spatial hash: {:?} each",
+    start.elapsed() / UNITS as u32
+  );
+}
@@ -0,0 +1,212 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::iter::Peekable;
+
+use crate::game::State;
+
+// Everything in the simulation at one moment, written out as text, for
+// telling whether two runs of a game have gone differently and where. Two
+// states that would play out the same way always have the same snapshot.
+//
+// It's made up of sections, one for each unit, building, and resource node,
+// plus a few for everything else, in an order that only depends on the state.
+#[derive(PartialEq)]
+pub struct Snapshot {
+  pub sections: Vec<Section>,
+}
+
+#[derive(PartialEq)]
+pub struct Section {
+  // Like "unit 4:0".
+  pub name: String,
+  pub lines: Vec<String>,
+}
+
+impl Snapshot {
+  pub fn new() -> Snapshot {
+    Snapshot { sections: vec![] }
+  }
+
+  pub fn section(&mut self, name: String, lines: Vec<String>) {
+    self.sections.push(Section { name, lines });
+  }
+
+  // A hash of the whole snapshot. Unlike std's hashers, FNV-1a is the same
+  // everywhere and always will be, so checksums from different machines and
+  // builds can be compared.
+  pub fn checksum(&self) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for section in self.sections.iter() {
+      let text = std::iter::once(&section.name).chain(section.lines.iter());
+      for byte in text.flat_map(|line| line.bytes().chain([b'\n'])) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+      }
+    }
+    hash
+  }
+
+  // Says what's different about the first section that isn't the same in the
+  // other snapshot, which is from an earlier run. None if they're the same.
+  pub fn first_difference(&self, earlier: &Snapshot) -> Option<String> {
+    let find = |snapshot: &Snapshot, name: &str| {
+      snapshot
+        .sections
+        .iter()
+        .position(|section| section.name == name)
+    };
+    let sections = self.sections.len().max(earlier.sections.len());
+    for i in 0..sections {
+      let (mine, theirs) = (self.sections.get(i), earlier.sections.get(i));
+      if mine == theirs {
+        continue;
+      }
+      let (section, other) = match (mine, theirs) {
+        (Some(mine), Some(theirs)) if mine.name == theirs.name => (mine, theirs),
+        (Some(mine), _) if find(earlier, &mine.name).is_none() => {
+          return Some(format!("{} is only in this run", mine.name));
+        }
+        (_, Some(theirs)) if find(self, &theirs.name).is_none() => {
+          return Some(format!("{} is only in the earlier run", theirs.name));
+        }
+        _ => {
+          let name = mine.or(theirs).map_or("", |section| &section.name);
+          return Some(format!(
+            "things are in a different order from the earlier run, from {} on",
+            name
+          ));
+        }
+      };
+      let mut out = format!("{} is different:", section.name);
+      let lines = section.lines.len().max(other.lines.len());
+      for j in 0..lines {
+        let (mine, theirs) = (section.lines.get(j), other.lines.get(j));
+        if mine != theirs {
+          let show = |line: Option<&String>| line.map_or("(nothing)".to_string(), |l| l.clone());
+          out += &format!("\n  this run:    {}", show(mine));
+          out += &format!("\n  earlier run: {}", show(theirs));
+        }
+      }
+      return Some(out);
+    }
+    None
+  }
+
+  // Writes the snapshot in the form that read_snapshot reads back.
+  fn write(&self, out: &mut impl Write, tick: u32) -> io::Result<()> {
+    writeln!(out, "tick {} checksum {:016x}", tick, self.checksum())?;
+    for section in self.sections.iter() {
+      writeln!(out, "{}", section.name)?;
+      for line in section.lines.iter() {
+        writeln!(out, "  {}", line)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+// Reads the next snapshot written by Snapshot::write, along with its tick.
+fn read_snapshot(
+  lines: &mut Peekable<Lines<BufReader<File>>>,
+) -> io::Result<Option<(u32, Snapshot)>> {
+  let header = match lines.next() {
+    Some(line) => line?,
+    None => return Ok(None),
+  };
+  let tick = header
+    .strip_prefix("tick ")
+    .and_then(|rest| rest.split(' ').next())
+    .and_then(|tick| tick.parse().ok())
+    .ok_or_else(|| io::Error::other(format!("expected a tick, not {:?}", header)))?;
+  let mut snapshot = Snapshot::new();
+  while let Some(Ok(line)) = lines.next_if(|line| !matches!(line, Ok(l) if l.starts_with("tick ")))
+  {
+    match (line.strip_prefix("  "), snapshot.sections.last_mut()) {
+      (Some(line), Some(section)) => section.lines.push(line.to_string()),
+      _ => snapshot.section(line, vec![]),
+    }
+  }
+  Ok(Some((tick, snapshot)))
+}
+
+// Looks at the game's state after every tick: printing its checksum, writing
+// its snapshot to a log, or checking it against a log from an earlier run.
+// When it stops matching the earlier run, the first difference is printed.
+pub struct StateChecker {
+  print: bool,
+  record: Option<BufWriter<File>>,
+  compare: Option<Peekable<Lines<BufReader<File>>>>,
+}
+
+impl StateChecker {
+  pub fn new(
+    print: bool,
+    record_path: Option<&str>,
+    compare_path: Option<&str>,
+  ) -> io::Result<StateChecker> {
+    let record = record_path
+      .map(|path| File::create(path).map(BufWriter::new))
+      .transpose()?;
+    let compare = compare_path
+      .map(|path| File::open(path).map(|f| BufReader::new(f).lines().peekable()))
+      .transpose()?;
+    Ok(StateChecker {
+      print,
+      record,
+      compare,
+    })
+  }
+
+  pub fn check(&mut self, state: &State) {
+    if !self.print && self.record.is_none() && self.compare.is_none() {
+      return;
+    }
+    let tick = state.time.ticks;
+    let snapshot = state.snapshot();
+    if self.print {
+      println!("tick {} checksum {:016x}", tick, snapshot.checksum());
+    }
+    if let Some(out) = &mut self.record {
+      if let Err(e) = snapshot.write(out, tick) {
+        println!("couldn't record state: {}", e);
+        self.record = None;
+      }
+    }
+    let lines = match &mut self.compare {
+      Some(lines) => lines,
+      None => return,
+    };
+    // Once the runs have gone differently there's no point carrying on.
+    match read_snapshot(lines) {
+      Ok(Some((earlier_tick, _))) if earlier_tick != tick => {
+        println!(
+          "desync: the earlier run has tick {} where this one has tick {}",
+          earlier_tick, tick
+        );
+        self.compare = None;
+      }
+      Ok(Some((_, earlier))) => {
+        if earlier.checksum() != snapshot.checksum() {
+          println!(
+            "desync at tick {}: checksum {:016x}, but {:016x} in the earlier run",
+            tick,
+            snapshot.checksum(),
+            earlier.checksum()
+          );
+          if let Some(difference) = snapshot.first_difference(&earlier) {
+            println!("{}", difference);
+          }
+          self.compare = None;
+        }
+      }
+      Ok(None) => {
+        println!("the earlier run ended before tick {}", tick);
+        self.compare = None;
+      }
+      Err(e) => {
+        println!("couldn't read the earlier run's state: {}", e);
+        self.compare = None;
+      }
+    }
+  }
+}
@@ -1,12 +1,20 @@
 pub use sdl2::rect::Point as WindowPoint;
 
-use std::cmp::{Ordering, PartialOrd};
-use std::ops::{Add, Div, Mul, Neg, Sub, SubAssign};
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
 
 const PIXELS_PER_WORLD: f32 = 1.;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub struct WorldCoord(pub f32);
+// How many bits of a WorldCoord are after the point.
+const FRACTION_BITS: u32 = 16;
+
+// A distance or position in the world, as a fixed-point number with
+// FRACTION_BITS bits after the point. The simulation only ever does integer
+// arithmetic on these, so it comes out exactly the same on every machine,
+// which replays and lockstep multiplayer rely on. Floats go in and out only at
+// the edges, like for constants and rendering.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WorldCoord(i64);
 impl Neg for WorldCoord {
   type Output = Self;
   fn neg(self) -> Self {
@@ -28,13 +36,18 @@ impl Sub for WorldCoord {
 impl Mul for WorldCoord {
   type Output = Self;
   fn mul(self, rhs: Self) -> Self {
-    WorldCoord(self.0 * rhs.0)
+    WorldCoord(((self.0 as i128 * rhs.0 as i128) >> FRACTION_BITS) as i64)
   }
 }
 impl Div for WorldCoord {
   type Output = Self;
   fn div(self, rhs: Self) -> Self {
-    WorldCoord(self.0 / rhs.0)
+    WorldCoord((((self.0 as i128) << FRACTION_BITS) / rhs.0 as i128) as i64)
+  }
+}
+impl AddAssign for WorldCoord {
+  fn add_assign(&mut self, rhs: WorldCoord) {
+    *self = *self + rhs;
   }
 }
 impl SubAssign for WorldCoord {
@@ -42,13 +55,61 @@ impl SubAssign for WorldCoord {
     *self = *self - rhs;
   }
 }
-impl PartialOrd for WorldCoord {
-  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    f32::partial_cmp(&self.0, &other.0)
+impl fmt::Debug for WorldCoord {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "WorldCoord({})", self)
+  }
+}
+
+// Exactly, in decimal.
+impl fmt::Display for WorldCoord {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let sign = if self.0 < 0 { "-" } else { "" };
+    let abs = self.0.unsigned_abs();
+    let whole = abs >> FRACTION_BITS;
+    let fraction = abs & ((1 << FRACTION_BITS) - 1);
+    if fraction == 0 {
+      return write!(f, "{}{}", sign, whole);
+    }
+    // fraction / 2^FRACTION_BITS is fraction * 5^FRACTION_BITS /
+    // 10^FRACTION_BITS, which has exactly FRACTION_BITS decimal places.
+    let digits = format!(
+      "{:0width$}",
+      fraction * 5u64.pow(FRACTION_BITS),
+      width = FRACTION_BITS as usize
+    );
+    write!(f, "{}{}.{}", sign, whole, digits.trim_end_matches('0'))
   }
 }
 
 impl WorldCoord {
+  pub const ZERO: WorldCoord = WorldCoord(0);
+  // Further than anything is from anything else.
+  pub const MAX: WorldCoord = WorldCoord(i64::MAX);
+
+  // The nearest coordinate at or towards zero from x.
+  pub const fn from_f32(x: f32) -> WorldCoord {
+    WorldCoord((x * (1u64 << FRACTION_BITS) as f32) as i64)
+  }
+
+  pub const fn from_int(x: i64) -> WorldCoord {
+    WorldCoord(x << FRACTION_BITS)
+  }
+
+  // For rendering, and anything else outside the simulation.
+  pub fn to_f32(self) -> f32 {
+    self.0 as f32 / (1u64 << FRACTION_BITS) as f32
+  }
+
+  // The whole number at or below this.
+  pub fn floor(self) -> i64 {
+    self.0 >> FRACTION_BITS
+  }
+
+  pub fn abs(self) -> WorldCoord {
+    WorldCoord(self.0.abs())
+  }
+
   pub fn clamp(self, lower: WorldCoord, upper: WorldCoord) -> WorldCoord {
     WorldCoord(self.0.clamp(lower.0, upper.0))
   }
@@ -64,14 +125,15 @@ impl WorldPoint {
     WorldPoint { x, y }
   }
 
+  // Rounded down to the nearest coordinate.
   pub fn magnitude(self) -> WorldCoord {
-    let (x, y) = (self.x, self.y);
-    WorldCoord(f32::sqrt((x * x + y * y).0))
+    let (x, y) = (self.x.0 as i128, self.y.0 as i128);
+    WorldCoord((x * x + y * y).unsigned_abs().isqrt() as i64)
   }
 
   pub fn normalized(self) -> WorldPoint {
     let (x, y) = (self.x, self.y);
-    if x == WorldCoord(0.) && y == WorldCoord(0.) {
+    if x == WorldCoord::ZERO && y == WorldCoord::ZERO {
       return self;
     }
     let magnitude = self.magnitude();
@@ -81,8 +143,8 @@ impl WorldPoint {
   pub fn to_window(self, camera: WorldPoint) -> WindowPoint {
     let offset = self - camera;
     WindowPoint::new(
-      (offset.x.0 * PIXELS_PER_WORLD) as i32,
-      (offset.y.0 * PIXELS_PER_WORLD) as i32,
+      (offset.x.to_f32() * PIXELS_PER_WORLD) as i32,
+      (offset.y.to_f32() * PIXELS_PER_WORLD) as i32,
     )
   }
 
@@ -144,8 +206,8 @@ pub trait ToWorld {
 impl ToWorld for WindowPoint {
   fn to_world(self, camera: WorldPoint) -> WorldPoint {
     WorldPoint {
-      x: WorldCoord(self.x() as f32 / PIXELS_PER_WORLD),
-      y: WorldCoord(self.y() as f32 / PIXELS_PER_WORLD),
+      x: WorldCoord::from_f32(self.x() as f32 / PIXELS_PER_WORLD),
+      y: WorldCoord::from_f32(self.y() as f32 / PIXELS_PER_WORLD),
     } + camera
   }
 }
@@ -158,6 +220,16 @@ pub struct WorldRect {
 }
 
 impl WorldRect {
+  // The rect with corners at a and b.
+  pub fn from_points(a: WorldPoint, b: WorldPoint) -> WorldRect {
+    let (left, top) = (a.x.min(b.x), a.y.min(b.y));
+    WorldRect {
+      top_left: WorldPoint::new(left, top),
+      width: a.x.max(b.x) - left,
+      height: a.y.max(b.y) - top,
+    }
+  }
+
   pub fn contains(&self, p: WorldPoint) -> bool {
     self.top_left.x <= p.x
       && p.x <= self.top_left.x + self.width
@@ -165,6 +237,39 @@ impl WorldRect {
       && p.y <= self.top_left.y + self.height
   }
 
+  // Distance from p to the closest point in the rect. Zero if p is inside.
+  pub fn distance_to(&self, p: WorldPoint) -> WorldCoord {
+    (p - p.clamp(self)).magnitude()
+  }
+
+  // Whether the line segment from a to b passes through the rect, edges
+  // included.
+  pub fn intersects_segment(&self, a: WorldPoint, b: WorldPoint) -> bool {
+    // Narrow down the part of the segment that's inside the rect, one axis at
+    // a time. t goes from 0 at a to 1 at b.
+    let (mut t_min, mut t_max) = (WorldCoord::ZERO, WorldCoord::from_int(1));
+    let axes = [
+      (a.x, b.x, self.top_left.x, self.top_left.x + self.width),
+      (a.y, b.y, self.top_left.y, self.top_left.y + self.height),
+    ];
+    for (from, to, low, high) in axes {
+      let d = to - from;
+      if d == WorldCoord::ZERO {
+        if from < low || high < from {
+          return false;
+        }
+        continue;
+      }
+      let (t1, t2) = ((low - from) / d, (high - from) / d);
+      t_min = t_min.max(t1.min(t2));
+      t_max = t_max.min(t1.max(t2));
+      if t_min > t_max {
+        return false;
+      }
+    }
+    true
+  }
+
   pub fn intersects(&self, other: &WorldRect) -> bool {
     let (p1, p2, p3, p4) = self.points();
     let (q1, q2, q3, q4) = other.points();
@@ -181,8 +286,8 @@ impl WorldRect {
   fn points(&self) -> (WorldPoint, WorldPoint, WorldPoint, WorldPoint) {
     (
       self.top_left,
-      self.top_left + WorldPoint::new(self.width, WorldCoord(0.)),
-      self.top_left + WorldPoint::new(WorldCoord(0.), self.height),
+      self.top_left + WorldPoint::new(self.width, WorldCoord::ZERO),
+      self.top_left + WorldPoint::new(WorldCoord::ZERO, self.height),
       self.top_left + WorldPoint::new(self.width, self.height),
     )
   }
@@ -201,8 +306,8 @@ impl DisplayPoint {
 
   pub fn to_world(self) -> WorldPoint {
     WorldPoint {
-      x: WorldCoord(self.x as f32 / PIXELS_PER_WORLD),
-      y: WorldCoord(self.y as f32 / PIXELS_PER_WORLD),
+      x: WorldCoord::from_f32(self.x as f32 / PIXELS_PER_WORLD),
+      y: WorldCoord::from_f32(self.y as f32 / PIXELS_PER_WORLD),
     }
   }
 }
@@ -0,0 +1,222 @@
+use crate::game::{building_types, unit_types, PlayerId};
+use crate::map::{GridTile, Map, TilePoint};
+use crate::map_file::{Location, MapEntity, MapError, MapFile, FORMAT_VERSION};
+use crate::tiled;
+
+// The highest elevation level a map can have, since map files give levels as
+// single digits.
+pub const MAX_ELEVATION: u8 = 9;
+// The width and height of a map started from scratch.
+const NEW_MAP_SIZE: u32 = 32;
+// How much a resource node placed in the editor holds.
+const RESOURCE_NODE_AMOUNT: u32 = 500;
+
+// What clicking on the map does in the editor.
+#[derive(Clone, Copy)]
+pub enum Brush {
+  Tile(GridTile),
+  // Changes the elevation level of tiles, leaving the tiles themselves alone.
+  Elevation(u8),
+  StartLocation,
+  // Places a unit or building of the type at this index of unit_types or
+  // building_types.
+  Unit(usize),
+  Building(usize),
+  ResourceNode,
+}
+
+impl Brush {
+  // Whether the brush keeps painting as the mouse is dragged, rather than
+  // placing one thing per click.
+  pub fn paints_terrain(self) -> bool {
+    matches!(self, Brush::Tile(_) | Brush::Elevation(_))
+  }
+
+  pub fn name(self) -> String {
+    match self {
+      Brush::Tile(tile) => format!("tile {}", tile.to_char()),
+      Brush::Elevation(level) => format!("elevation {}", level),
+      Brush::StartLocation => "start location".to_string(),
+      Brush::Unit(i) => format!("unit {}", unit_types()[i].name),
+      Brush::Building(i) => format!("building {}", building_types()[i].name),
+      Brush::ResourceNode => "resource node".to_string(),
+    }
+  }
+}
+
+// A map that's open in the editor.
+pub struct Editor {
+  // Where the map gets saved.
+  pub path: String,
+  pub file: MapFile,
+  pub brush: Brush,
+  // The player that units and buildings get placed for.
+  pub owner: PlayerId,
+  // Whether the mouse is held down to paint terrain.
+  pub painting: bool,
+  // Whether there are changes that haven't been saved yet.
+  pub unsaved: bool,
+}
+
+impl Editor {
+  // Opens the map at path, or starts a new one if there isn't a file there
+  // yet.
+  pub fn open(path: &str) -> Result<Editor, MapError> {
+    let file = if std::path::Path::new(path).exists() {
+      MapFile::from_file(path)?
+    } else {
+      let size = NEW_MAP_SIZE * NEW_MAP_SIZE;
+      MapFile {
+        version: FORMAT_VERSION,
+        name: String::new(),
+        author: String::new(),
+        start_locations: vec![],
+        entities: vec![],
+        map: Map::new(
+          NEW_MAP_SIZE,
+          NEW_MAP_SIZE,
+          vec![GridTile::Empty; size as usize],
+        ),
+      }
+    };
+    Ok(Editor {
+      path: path.to_string(),
+      file,
+      brush: Brush::Tile(GridTile::Obstacle),
+      owner: 0,
+      painting: false,
+      unsaved: false,
+    })
+  }
+
+  // Uses the brush on the tile at p.
+  pub fn paint(&mut self, p: TilePoint) -> Result<(), String> {
+    let map = &mut self.file.map;
+    let (tile, level) = match map.get_tile(p) {
+      Some(tile) => (tile, map.elevation(p)),
+      None => return Ok(()),
+    };
+    let entity = match self.brush {
+      Brush::Tile(new_tile) => {
+        if new_tile != tile {
+          map.set_tile(p, new_tile, level);
+          self.unsaved = true;
+        }
+        return Ok(());
+      }
+      Brush::Elevation(new_level) => {
+        if new_level != level {
+          map.set_tile(p, tile, new_level);
+          self.unsaved = true;
+        }
+        return Ok(());
+      }
+      Brush::StartLocation => {
+        if !self.file.start_locations.contains(&p) {
+          self.file.start_locations.push(p);
+          self.unsaved = true;
+        }
+        return Ok(());
+      }
+      Brush::Unit(i) => MapEntity::Unit {
+        unit_type: unit_types()[i].name.to_string(),
+        owner: self.owner,
+        pos: p,
+      },
+      Brush::Building(i) => MapEntity::Building {
+        building_type: building_types()[i].name.to_string(),
+        owner: self.owner,
+        pos: p,
+      },
+      Brush::ResourceNode => MapEntity::ResourceNode {
+        pos: p,
+        amount: RESOURCE_NODE_AMOUNT,
+      },
+    };
+    let owned = !matches!(entity, MapEntity::ResourceNode { .. });
+    if owned && self.owner as usize >= self.file.start_locations.len() {
+      return Err(format!(
+        "player {} needs a start location before anything can be placed for them",
+        self.owner
+      ));
+    }
+    // Whatever was on the tile before gets replaced.
+    self.file.entities.retain(|(_, e)| e.pos() != p);
+    self
+      .file
+      .entities
+      .push((Location { line: 0, col: 0 }, entity));
+    self.unsaved = true;
+    Ok(())
+  }
+
+  // Removes any start location and entities on the tile at p. Removing a start
+  // location removes its player, along with everything they own.
+  pub fn erase(&mut self, p: TilePoint) {
+    let before = self.file.entities.len();
+    self.file.entities.retain(|(_, e)| e.pos() != p);
+    if self.file.entities.len() != before {
+      self.unsaved = true;
+    }
+    if let Some(i) = self.file.start_locations.iter().position(|q| *q == p) {
+      self.remove_player(i as PlayerId);
+    }
+  }
+
+  fn remove_player(&mut self, player: PlayerId) {
+    self.file.start_locations.remove(player as usize);
+    self
+      .file
+      .entities
+      .retain(|(_, e)| e.owner() != Some(player));
+    // Everyone after them moves up a place.
+    for (_, entity) in self.file.entities.iter_mut() {
+      if let MapEntity::Unit { owner, .. } | MapEntity::Building { owner, .. } = entity {
+        if *owner > player {
+          *owner -= 1;
+        }
+      }
+    }
+    if self.owner > player {
+      self.owner -= 1;
+    }
+    let last = self.file.start_locations.len().saturating_sub(1);
+    self.owner = self.owner.min(last as PlayerId);
+    self.unsaved = true;
+  }
+
+  // Changes the size of the map, keeping its top-left corner where it is.
+  // Anything left off the map is removed.
+  pub fn resize(&mut self, width: u32, height: u32) {
+    let (width, height) = (width.max(1), height.max(1));
+    self.file.map = self.file.map.resized(width, height);
+    let on_map = |p: TilePoint| p.x() < width && p.y() < height;
+    while let Some(i) = self.file.start_locations.iter().position(|p| !on_map(*p)) {
+      self.remove_player(i as PlayerId);
+    }
+    self.file.entities.retain(|(_, e)| on_map(e.pos()));
+    self.unsaved = true;
+  }
+
+  // Switches to placing things for the next player, going back to the first
+  // after the last.
+  pub fn next_owner(&mut self) {
+    let players = self.file.start_locations.len().max(1);
+    self.owner = ((self.owner as usize + 1) % players) as PlayerId;
+  }
+
+  pub fn save(&mut self) -> Result<(), String> {
+    // Writing our own format over a Tiled map would leave Tiled unable to
+    // open it.
+    if tiled::is_tiled_map(&self.path) {
+      return Err(format!(
+        "{} is a Tiled map; convert it with --import-tiled to edit it here",
+        self.path
+      ));
+    }
+    std::fs::write(&self.path, self.file.to_text())
+      .map_err(|e| format!("couldn't write {}: {}", self.path, e))?;
+    self.unsaved = false;
+    Ok(())
+  }
+}
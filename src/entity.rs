@@ -0,0 +1,134 @@
+use std::fmt;
+
+// UIDs are used to refer uniquely to buildings, units, or resource nodes.
+//
+// Each one is the index of a slot in the EntityStore, along with which
+// generation of that slot it was made in. When something dies its slot is
+// reused, but with the generation bumped, so that UIDs of dead things never
+// find whatever takes their place.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct UID {
+  pub index: u32,
+  pub generation: u32,
+}
+
+impl fmt::Display for UID {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.index, self.generation)
+  }
+}
+
+// Where in the game state something with a UID is: an index into its list of
+// units, buildings, or resource nodes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntityRef {
+  Unit(usize),
+  Building(usize),
+  ResourceNode(usize),
+}
+
+// Hands out UIDs, and finds what each one refers to without searching.
+// Units, buildings, and resource nodes all share the same UIDs.
+pub struct EntityStore {
+  slots: Vec<Slot>,
+  // Slots with nothing in them, to be reused before making new ones.
+  free: Vec<u32>,
+}
+
+struct Slot {
+  generation: u32,
+  entity: Option<EntityRef>,
+}
+
+impl EntityStore {
+  pub fn new() -> EntityStore {
+    EntityStore {
+      slots: vec![],
+      free: vec![],
+    }
+  }
+
+  // A new UID referring to entity.
+  pub fn add(&mut self, entity: EntityRef) -> UID {
+    if let Some(index) = self.free.pop() {
+      let slot = &mut self.slots[index as usize];
+      slot.entity = Some(entity);
+      return UID {
+        index,
+        generation: slot.generation,
+      };
+    }
+    self.slots.push(Slot {
+      generation: 0,
+      entity: Some(entity),
+    });
+    UID {
+      index: (self.slots.len() - 1) as u32,
+      generation: 0,
+    }
+  }
+
+  // Forgets the UID, so that it no longer refers to anything. Does nothing if
+  // it already doesn't.
+  pub fn remove(&mut self, uid: UID) {
+    if self.get(uid).is_none() {
+      return;
+    }
+    let slot = &mut self.slots[uid.index as usize];
+    slot.entity = None;
+    slot.generation = slot.generation.wrapping_add(1);
+    self.free.push(uid.index);
+  }
+
+  // Points the UID at where its entity is now, after its list has changed.
+  pub fn relocate(&mut self, uid: UID, entity: EntityRef) {
+    if self.get(uid).is_some() {
+      self.slots[uid.index as usize].entity = Some(entity);
+    }
+  }
+
+  // What the UID refers to, or None if it's gone.
+  pub fn get(&self, uid: UID) -> Option<EntityRef> {
+    self
+      .slots
+      .get(uid.index as usize)
+      .filter(|slot| slot.generation == uid.generation)
+      .and_then(|slot| slot.entity)
+  }
+
+  // Each slot's generation, and which slots are free, in the order they'll
+  // be reused.
+  pub fn describe(&self) -> Vec<String> {
+    let generations: Vec<String> = self
+      .slots
+      .iter()
+      .map(|slot| slot.generation.to_string())
+      .collect();
+    vec![
+      format!("generations {}", generations.join(" ")),
+      format!("free {:?}", self.free),
+    ]
+  }
+
+  // The index of the unit with this UID, if it's a unit that's still alive.
+  pub fn unit(&self, uid: UID) -> Option<usize> {
+    match self.get(uid) {
+      Some(EntityRef::Unit(i)) => Some(i),
+      _ => None,
+    }
+  }
+
+  pub fn building(&self, uid: UID) -> Option<usize> {
+    match self.get(uid) {
+      Some(EntityRef::Building(i)) => Some(i),
+      _ => None,
+    }
+  }
+
+  pub fn resource_node(&self, uid: UID) -> Option<usize> {
+    match self.get(uid) {
+      Some(EntityRef::ResourceNode(i)) => Some(i),
+      _ => None,
+    }
+  }
+}
@@ -0,0 +1,208 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::map::{Map, TilePoint};
+
+// The most map tiles' worth of flow fields to keep around at once. Each field
+// takes five bytes per tile, so this is about 20MB, however big the map is.
+const MAX_CACHED_TILES: usize = 4 << 20;
+
+// What a flow field stores instead of a Some(u32) cost, for tiles that can't
+// reach dest. No path costs anywhere near this much.
+const UNREACHABLE: u32 = u32::MAX;
+
+// The ways a flow field can point from a tile, as stored in the field. The
+// tile itself, for dest and tiles that can't reach it, is 0.
+const DIRECTIONS: [(i64, i64); 4] = [(-1, 0), (0, -1), (1, 0), (0, 1)];
+
+// A flow field says, for every tile on the map, which way to go to get to one
+// destination tile. It's built with a single search outwards from the
+// destination, after which any number of units can find their way there by
+// looking up the tile they're on. This makes it much cheaper than a search
+// per unit when a big group is heading to the same place.
+pub struct FlowField {
+  pub dest: TilePoint,
+  // The clearance units using the field need; see Map::clearance.
+  pub clearance: u8,
+  width: u32,
+  height: u32,
+  // The integration field: the cost of the cheapest path from each tile to
+  // dest, or UNREACHABLE if dest can't be reached from the tile.
+  costs: Vec<u32>,
+  // The direction field: which way the next tile along a cheapest path from
+  // each tile to dest is, as 1 plus its index in DIRECTIONS. 0 for dest itself,
+  // and for tiles that can't reach it.
+  next: Vec<u8>,
+}
+
+impl FlowField {
+  pub fn new(map: &Map, dest: TilePoint, clearance: u8) -> FlowField {
+    let size = (map.width * map.height) as usize;
+    let mut field = FlowField {
+      dest,
+      clearance,
+      width: map.width,
+      height: map.height,
+      costs: vec![UNREACHABLE; size],
+      next: vec![0; size],
+    };
+    let dest_index = match field.index(dest) {
+      Some(i) => i,
+      None => return field,
+    };
+
+    // Dijkstra's algorithm outwards from dest. Each tile points back at the
+    // tile its cheapest path came through. dest itself doesn't have to be
+    // walkable, so units can head for a building and stop when they bump into
+    // it.
+    field.costs[dest_index] = 0;
+    let mut to_visit = BinaryHeap::new();
+    to_visit.push(Reverse((0, dest)));
+    while let Some(Reverse((cost, here))) = to_visit.pop() {
+      if field.cost(here).is_some_and(|best| best < cost) {
+        continue;
+      }
+      for p in here.neighbors4(map) {
+        if !map.has_clearance(p, clearance) || !map.can_cross(p, here) {
+          continue;
+        }
+        let i = field.index(p).unwrap();
+        let next_cost = cost + map.step_cost(here, p);
+        if field.costs[i] <= next_cost {
+          continue;
+        }
+        let step = (
+          here.x() as i64 - p.x() as i64,
+          here.y() as i64 - p.y() as i64,
+        );
+        field.costs[i] = next_cost;
+        field.next[i] = DIRECTIONS.iter().position(|d| *d == step).unwrap() as u8 + 1;
+        to_visit.push(Reverse((next_cost, p)));
+      }
+    }
+    field
+  }
+
+  fn index(&self, p: TilePoint) -> Option<usize> {
+    if p.x() >= self.width || p.y() >= self.height {
+      return None;
+    }
+    Some((p.x() + p.y() * self.width) as usize)
+  }
+
+  // How many tiles the field covers.
+  pub fn len(&self) -> usize {
+    self.costs.len()
+  }
+
+  // The cost of the cheapest path from p to dest, if there is one.
+  pub fn cost(&self, p: TilePoint) -> Option<u32> {
+    self
+      .index(p)
+      .map(|i| self.costs[i])
+      .filter(|cost| *cost != UNREACHABLE)
+  }
+
+  // The tile to step to from p to get closer to dest.
+  pub fn next(&self, p: TilePoint) -> Option<TilePoint> {
+    let direction = self.index(p).map_or(0, |i| self.next[i]);
+    let (dx, dy) = DIRECTIONS.get((direction as usize).checked_sub(1)?)?;
+    Some(TilePoint::new(
+      (p.x() as i64 + dx) as u32,
+      (p.y() as i64 + dy) as u32,
+    ))
+  }
+
+  // Follows the field from src to dest. Returns every tile along the way,
+  // including both ends, or None if dest can't be reached.
+  //
+  // Units can get pushed a little way into tiles they don't fit on, which are
+  // never in the field, so if src is one of those we step out of it to
+  // whichever neighbour is closest to dest.
+  pub fn path_from(&self, map: &Map, src: TilePoint) -> Option<Vec<TilePoint>> {
+    let mut path = vec![src];
+    let mut here = if self.cost(src).is_some() {
+      src
+    } else {
+      let out = src
+        .neighbors4(map)
+        .into_iter()
+        .filter_map(|p| self.cost(p).map(|cost| (cost, p)))
+        .min_by_key(|(cost, _)| *cost)?
+        .1;
+      path.push(out);
+      out
+    };
+    while let Some(next) = self.next(here) {
+      path.push(next);
+      here = next;
+    }
+    Some(path)
+  }
+}
+
+// Keeps the flow fields that have been built so far, so that ordering units to
+// a tile that something has already been sent to doesn't cost anything. When
+// the fields add up to too many tiles, the ones used least recently are thrown
+// away.
+//
+// Only groups moving together use flow fields; see Pathfinder::find_path. And
+// units don't look the field up every tick: each one follows it once, when
+// its path is found, and then walks the waypoints that gives it like any
+// other path. A unit on its own gets its path from the HPA* graph instead.
+pub struct FlowFieldCache {
+  // Fields by their destination and clearance, along with when each was last
+  // used.
+  fields: HashMap<(TilePoint, u8), (FlowField, u64)>,
+  // How many tiles all the fields cover between them.
+  tiles: usize,
+  // Goes up by one every time a field is asked for.
+  clock: u64,
+}
+
+impl FlowFieldCache {
+  pub fn new() -> FlowFieldCache {
+    FlowFieldCache {
+      fields: HashMap::new(),
+      tiles: 0,
+      clock: 0,
+    }
+  }
+
+  // The flow field towards dest for units needing the given clearance,
+  // building it if we don't have it yet.
+  pub fn get(&mut self, map: &Map, dest: TilePoint, clearance: u8) -> &FlowField {
+    let key = (dest, clearance);
+    self.clock += 1;
+    if !self.fields.contains_key(&key) {
+      let field = FlowField::new(map, dest, clearance);
+      // Always keep the new field, even if it's too big by itself.
+      while !self.fields.is_empty() && self.tiles + field.len() > MAX_CACHED_TILES {
+        self.evict_least_recently_used();
+      }
+      self.tiles += field.len();
+      self.fields.insert(key, (field, 0));
+    }
+    let (field, last_used) = self.fields.get_mut(&key).unwrap();
+    *last_used = self.clock;
+    field
+  }
+
+  fn evict_least_recently_used(&mut self) {
+    let oldest = self
+      .fields
+      .iter()
+      .min_by_key(|(_, (_, last_used))| *last_used)
+      .map(|(key, _)| *key);
+    if let Some((field, _)) = oldest.and_then(|key| self.fields.remove(&key)) {
+      self.tiles -= field.len();
+    }
+  }
+
+  // Throws away every field. Must be called whenever the map changes, since
+  // the fields would point units the wrong way.
+  pub fn clear(&mut self) {
+    self.fields.clear();
+    self.tiles = 0;
+  }
+}
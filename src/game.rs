@@ -1,14 +1,39 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-use crate::ability::{Ability, AbilityBuild, AbilityTrain};
+use crate::ability::{
+  Ability, AbilityAttackMove, AbilityBuild, AbilityHeal, AbilityHold, AbilityPatrol, AbilityStop,
+  AbilityTrain, CastError, PointTargetedAbility,
+};
+use crate::checksum::Snapshot;
 use crate::dimensions::{WorldCoord as Coord, WorldPoint as Point, WorldRect as Rect};
-use crate::map::{GridTile, Map, TilePoint, ToTilePoint};
+use crate::entity::{EntityRef, EntityStore, UID};
+use crate::map::{clearance_needed, Map, TilePoint, ToTilePoint, TILE_WIDTH, TILE_WIDTH_COORD};
+use crate::map_file::{MapEntity, MapError, MapFile};
+use crate::pathfinding::Pathfinder;
+use crate::spatial::SpatialHash;
 use crate::sprite_sheet::SpriteKey;
+use crate::vision::Vision;
 
 const TICKS_PER_SEC: u32 = 120; // TODO: Drop to 24 when fps and tps differ.
 
-#[derive(Clone, Copy, PartialEq)]
+// How far away units on attack-move, patrol, etc. will notice enemies.
+const ACQUIRE_RANGE: Coord = Coord::from_int(4 * TILE_WIDTH as i64);
+// How close a following unit tries to stay to the unit it's following.
+const FOLLOW_DISTANCE: Coord = TILE_WIDTH_COORD;
+// How close a unit has to be to touch a building or resource.
+const REACH: Coord = Coord::from_int(8);
+// How spread out a group of units can be before a group move packs them into
+// a compact formation, rather than keeping their layout.
+const FORMATION_MAX_SPREAD: Coord = Coord::from_int(4 * TILE_WIDTH as i64);
+// Space left between neighbouring units in a compact formation.
+const FORMATION_GAP: Coord = Coord::from_int(4);
+const GATHER_AMOUNT: u32 = 5;
+const GATHER_SECS: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct GameDur {
   pub ticks: u32,
 }
@@ -18,134 +43,937 @@ impl GameDur {
       ticks: secs * TICKS_PER_SEC,
     }
   }
+
+  // Whole seconds left in the duration, rounding up.
+  pub fn secs_ceil(&self) -> u32 {
+    self.ticks.div_ceil(TICKS_PER_SEC)
+  }
 }
 
-// UIDs are used to refer uniquely to buildings or units.
-pub type UID = u32;
+// Players are numbered from 0. Anything owned by another player is an enemy.
+pub type PlayerId = u8;
 
 pub struct State {
+  pub players: Vec<Player>,
   pub units: Vec<Unit>,
   pub unit_types: Vec<UnitType>,
   pub buildings: Vec<Building>,
   pub building_types: Vec<BuildingType>,
+  pub resource_nodes: Vec<ResourceNode>,
   pub map: Map,
-  pub next_uid: UID,
+  pub pathfinder: Pathfinder,
+  // Each unit's UID and owner, by where it is.
+  pub unit_index: SpatialHash<(UID, PlayerId)>,
+  // What each UID refers to.
+  pub entities: EntityStore,
+  // How long the game has been going.
+  pub time: GameDur,
 }
 
 impl State {
-  pub fn blank() -> State {
+  pub fn blank(map: Map) -> State {
     State {
+      players: vec![],
       units: vec![],
       unit_types: vec![],
       buildings: vec![],
       building_types: vec![],
+      resource_nodes: vec![],
 
-      map: Map::from_file("media/test-map.txt").expect("couldn't load the map"),
+      pathfinder: Pathfinder::new(&map),
+      unit_index: SpatialHash::new(&map),
+      map,
 
-      next_uid: 0,
+      entities: EntityStore::new(),
+      time: GameDur { ticks: 0 },
     }
   }
 
-  pub fn level1() -> State {
-    let mut state = State::blank();
-    let newt_type = UnitType {
-      name: "Newt",
-      sprite_key: "newt_gingrich".to_string(),
-      radius: Coord(16.),
-      base_speed: Coord(1.),
-    };
-    state.unit_types.push(newt_type.clone());
-    let town_hall_type = BuildingType {
-      name: "Town Hall",
-      width: 1,
-      height: 1,
-    };
-    state.building_types.push(town_hall_type.clone());
+  // Starts a game on the map in the file at path, with a player for each of
+  // the map's start locations.
+  pub fn from_map_file(path: &str) -> Result<State, MapError> {
+    State::from_map(path, MapFile::from_file(path)?)
+  }
+
+  // Starts a game on a map that's already been read from the file at path.
+  // The path is only used in errors.
+  pub fn from_map(path: &str, map_file: MapFile) -> Result<State, MapError> {
+    let mut state = State::blank(map_file.map);
+    state.unit_types = unit_types();
+    state.building_types = building_types();
 
-    state.make_unit(newt_type, Point::new(Coord(300.), Coord(250.)));
-    state.make_building(town_hall_type, TilePoint::new(1, 1));
-    state
+    // Maps without any start locations, like those in the original format,
+    // still get someone to play as.
+    for _ in 0..map_file.start_locations.len().max(1) {
+      state.players.push(Player {
+        resources: 0,
+        vision: Vision::new(&state.map),
+      });
+    }
+    for (loc, entity) in map_file.entities {
+      match entity {
+        MapEntity::Unit {
+          unit_type,
+          owner,
+          pos,
+        } => {
+          let unit_type = state
+            .unit_types
+            .iter()
+            .find(|t| t.name == unit_type)
+            .ok_or(MapError::at(
+              path,
+              loc,
+              format!("unknown unit type {:?}", unit_type),
+            ))?
+            .clone();
+          state.make_unit(unit_type, pos.center_to_world_point(), owner);
+        }
+        MapEntity::Building {
+          building_type,
+          owner,
+          pos,
+        } => {
+          let building_type = state
+            .building_types
+            .iter()
+            .find(|t| t.name == building_type)
+            .ok_or(MapError::at(
+              path,
+              loc,
+              format!("unknown building type {:?}", building_type),
+            ))?
+            .clone();
+          state.make_building(building_type, pos, owner);
+        }
+        MapEntity::ResourceNode { pos, amount } => {
+          state.make_resource_node(pos, amount);
+        }
+      }
+    }
+    state.update_vision();
+    Ok(state)
   }
 
   pub fn tick(&mut self) {
-    for unit in self.units.iter_mut() {
+    self.time.ticks += 1;
+    let mut casts = vec![];
+    for i in 0..self.units.len() {
+      let unit = &mut self.units[i];
+      unit.cooldowns.tick();
+      if let Some(energy) = &mut unit.energy {
+        energy.tick();
+      }
+      unit.attack_cooldown.ticks = unit.attack_cooldown.ticks.saturating_sub(1);
+
+      if let Some(cast) = self.update_orders(i) {
+        casts.push(cast);
+      }
+
       // If the unit is moving, move it.
+      let unit = &mut self.units[i];
       if let Some(target) = unit.waypoints.front() {
         let to_target = *target - unit.pos;
-        let speed = unit.speed();
+        let speed = unit.speed() * self.map.speed_multiplier_at(unit.pos);
         let (next_pos, is_last_step) = if to_target.magnitude() < speed {
           (*target, true)
         } else {
           (unit.pos + to_target.normalized() * speed, false)
         };
 
-        let tiles: Vec<_> = self
-          .map
-          .tiles_overlapping_rect(unit.bounding_box_at(next_pos))
+        // Units bump into anything unwalkable, and into the edges of cliffs
+        // between elevation levels. Only tiles the unit isn't already on block
+        // it, so that a unit caught under a new building can still walk out.
+        let map = &self.map;
+        let here = unit.pos.to_tile_point();
+        let blocked = |p: TilePoint| !map.is_walkable(p) || !map.can_cross(here, p);
+        let blocked_now: Vec<_> = map
+          .tiles_overlapping_rect(unit.bounding_box())
+          .filter(|item| blocked(item.pos))
+          .map(|item| item.pos)
           .collect();
-        let collision = tiles.iter().any(|item| item.tile == GridTile::Obstacle);
+        let collision = map
+          .tiles_overlapping_rect(unit.bounding_box_at(next_pos))
+          .any(|item| blocked(item.pos) && !blocked_now.contains(&item.pos));
         if collision {
           // TODO: Step up to the wall, but not through it.
         } else {
+          let old_pos = unit.pos;
           unit.pos = next_pos;
+          self
+            .unit_index
+            .update((unit.uid, unit.owner), old_pos, next_pos);
         }
         if is_last_step {
           unit.waypoints.pop_front();
         }
       }
     }
+    for (ability, target) in casts {
+      if let Err(e) = ability.cast(self, target) {
+        println!("couldn't cast {}: {}", ability.name(), e);
+      }
+    }
 
     let mut units_to_make = vec![];
     for building in self.buildings.iter_mut() {
+      building.cooldowns.tick();
+      if building.under_construction() {
+        continue;
+      }
+
       let mut done_training = false;
       if let Some(training) = building.train_queue.front_mut() {
         training.dur_left.ticks -= 1;
         done_training = training.dur_left.ticks == 0;
         if done_training {
-          units_to_make.push((training.unit_type.clone(), building.spawn_location()));
+          units_to_make.push((
+            training.unit_type.clone(),
+            building.spawn_location(),
+            building.owner,
+          ));
         }
       }
       if done_training {
         building.train_queue.pop_front();
       }
     }
-    for (unit, pos) in units_to_make {
-      self.make_unit(unit, pos);
+    for (unit, pos, owner) in units_to_make {
+      self.make_unit(unit, pos, owner);
+    }
+
+    for unit in self.units.iter().filter(|u| u.hp == 0) {
+      self.unit_index.remove((unit.uid, unit.owner), unit.pos);
+      self.entities.remove(unit.uid);
+    }
+    self.units.retain(|u| u.hp > 0);
+    for i in 0..self.buildings.len() {
+      if self.buildings[i].hp == 0 {
+        let (uid, top_left, width, height) = {
+          let b = &self.buildings[i];
+          (b.uid, b.top_left_pos, b.width(), b.height())
+        };
+        self.map.vacate(top_left, width, height);
+        self.pathfinder.update(&self.map, top_left, width, height);
+        self.entities.remove(uid);
+      }
+    }
+    self.buildings.retain(|b| b.hp > 0);
+    for node in self.resource_nodes.iter().filter(|r| r.amount == 0) {
+      self.entities.remove(node.uid);
     }
+    self.resource_nodes.retain(|r| r.amount > 0);
+    self.relocate_entities();
+    self.update_vision();
   }
 
-  fn next_uid(&mut self) -> UID {
-    let uid = self.next_uid;
-    if self.next_uid == UID::MAX {
-      println!("error: ran out of UIDs!");
+  // Everything in the simulation, for checking that two runs of a game
+  // haven't gone differently. What's selected and what each player can see
+  // aren't included: selection is up to each player, and vision is worked out
+  // from the rest. Neither are paths the pathfinder has cached.
+  pub fn snapshot(&self) -> Snapshot {
+    let point = |p: Point| format!("({}, {})", p.x, p.y);
+    let points = |ps: &VecDeque<Point>| ps.iter().map(|p| point(*p)).collect::<Vec<_>>().join(" ");
+    let mut snapshot = Snapshot::new();
+    snapshot.section("time".to_string(), vec![self.time.ticks.to_string()]);
+    for (i, player) in self.players.iter().enumerate() {
+      snapshot.section(
+        format!("player {}", i),
+        vec![format!("resources {}", player.resources)],
+      );
     }
-    self.next_uid += 1;
-    uid
+    for unit in self.units.iter() {
+      let orders: Vec<String> = unit.orders.iter().map(|o| o.describe()).collect();
+      let energy = unit.energy.map_or("no energy".to_string(), |e| {
+        format!("energy {}/{} regen {}", e.current, e.max, e.regen_progress)
+      });
+      snapshot.section(
+        format!("unit {}", unit.uid),
+        vec![
+          format!(
+            "{} owned by {}, hp {}",
+            unit.unit_type.name, unit.owner, unit.hp
+          ),
+          format!("pos {}", point(unit.pos)),
+          format!(
+            "orders [{}] started {}",
+            orders.join(", "),
+            unit.order_started
+          ),
+          format!("waypoints [{}]", points(&unit.waypoints)),
+          format!("cooldowns {}", unit.cooldowns.describe()),
+          energy,
+          format!(
+            "attack cooldown {}, carrying {}, gathering {}",
+            unit.attack_cooldown.ticks, unit.carrying, unit.gather_progress.ticks
+          ),
+        ],
+      );
+    }
+    for building in self.buildings.iter() {
+      let training: Vec<String> = building
+        .train_queue
+        .iter()
+        .map(|t| {
+          format!(
+            "{} {}/{}",
+            t.unit_type.name, t.dur_left.ticks, t.dur_total.ticks
+          )
+        })
+        .collect();
+      snapshot.section(
+        format!("building {}", building.uid),
+        vec![
+          format!(
+            "{} owned by {}, hp {}",
+            building.building_type.name, building.owner, building.hp
+          ),
+          format!("at {:?}", building.top_left_pos),
+          format!("construction left {}", building.construction_left.ticks),
+          format!("training [{}]", training.join(", ")),
+          format!("cooldowns {}", building.cooldowns.describe()),
+        ],
+      );
+    }
+    for node in self.resource_nodes.iter() {
+      snapshot.section(
+        format!("resource node {}", node.uid),
+        vec![format!("at {:?}, amount {}", node.pos, node.amount)],
+      );
+    }
+    snapshot.section("entities".to_string(), self.entities.describe());
+    snapshot.section("occupancy".to_string(), self.map.describe_occupancy());
+    snapshot
+  }
+
+  // A hash of the snapshot, which is the same for two runs of a game exactly
+  // as long as they've gone the same way.
+  pub fn checksum(&self) -> u64 {
+    self.snapshot().checksum()
   }
 
-  pub fn make_unit(&mut self, unit_type: UnitType, pos: Point) {
-    let uid = self.next_uid();
+  // Points every UID at where its unit, building, or resource node is now,
+  // after dead ones have been taken out from among them.
+  fn relocate_entities(&mut self) {
+    for (i, unit) in self.units.iter().enumerate() {
+      self.entities.relocate(unit.uid, EntityRef::Unit(i));
+    }
+    for (i, building) in self.buildings.iter().enumerate() {
+      self.entities.relocate(building.uid, EntityRef::Building(i));
+    }
+    for (i, node) in self.resource_nodes.iter().enumerate() {
+      self.entities.relocate(node.uid, EntityRef::ResourceNode(i));
+    }
+  }
+
+  // Works out what each player can see from where their units and buildings
+  // are now.
+  fn update_vision(&mut self) {
+    for player in self.players.iter_mut() {
+      player.vision.fade();
+    }
+    let units = self
+      .units
+      .iter()
+      .map(|u| (u.owner, u.pos.to_tile_point(), u.unit_type.sight));
+    let buildings = self
+      .buildings
+      .iter()
+      .map(|b| (b.owner, b.center().to_tile_point(), b.building_type.sight));
+    // Units standing together see the same things, so each place only needs
+    // working out once.
+    let seers: HashSet<(PlayerId, TilePoint, u32)> = units.chain(buildings).collect();
+    for (owner, p, sight) in seers {
+      if let Some(player) = self.players.get_mut(owner as usize) {
+        player.vision.reveal(&self.map, p, sight);
+      }
+    }
+  }
+
+  // Whether player can see p right now. Anything owned by a player that isn't
+  // in the game can see everything.
+  pub fn can_see(&self, player: PlayerId, p: Point) -> bool {
+    self
+      .players
+      .get(player as usize)
+      .is_none_or(|player| player.vision.is_visible(p.to_tile_point()))
+  }
+
+  // Starts, continues, or finishes the current order of the unit at index i.
+  // Returns the ability cast that the unit should make this tick, if any.
+  fn update_orders(&mut self, i: usize) -> Option<(Rc<dyn PointTargetedAbility>, Point)> {
+    let order = self.units[i].orders.front()?.clone();
+    let (uid, pos, owner) = {
+      let unit = &self.units[i];
+      (unit.uid, unit.pos, unit.owner)
+    };
+    match order {
+      Order::Move(dest) => {
+        let unit = &mut self.units[i];
+        if unit.walk_to(&self.map, &mut self.pathfinder, dest, None) {
+          unit.finish_order();
+        }
+      }
+
+      Order::FormationMove {
+        dest, group_dest, ..
+      } => {
+        let unit = &mut self.units[i];
+        if unit.walk_to(&self.map, &mut self.pathfinder, dest, Some(group_dest)) {
+          unit.finish_order();
+        }
+      }
+
+      Order::AttackMove(dest) => {
+        if !self.engage_enemies(i) {
+          let unit = &mut self.units[i];
+          if unit.walk_to(&self.map, &mut self.pathfinder, dest, None) {
+            unit.finish_order();
+          }
+        }
+      }
+
+      Order::Attack(target) => {
+        // Units that can't attack give up straight away, rather than chasing
+        // their target forever.
+        let (target_pos, distance) = match self.locate(target, pos) {
+          Some(found) if target != uid && self.units[i].unit_type.attack.is_some() => found,
+          _ => {
+            self.units[i].finish_order();
+            return None;
+          }
+        };
+        // Units have to be able to see what they attack. Units on low ground
+        // can't see up onto high ground, so they have to climb up to attack
+        // anything there.
+        let visible = self.map.line_of_sight(pos, target_pos);
+        let unit = &mut self.units[i];
+        match unit.unit_type.attack {
+          Some(attack) if distance <= attack.range && visible => {
+            unit.waypoints.clear();
+            self.attack(i, target);
+          }
+          _ => unit.chase(&self.map, &mut self.pathfinder, target_pos),
+        }
+      }
+
+      Order::Patrol { from, to } => {
+        if !self.engage_enemies(i) {
+          let unit = &mut self.units[i];
+          if unit.walk_to(&self.map, &mut self.pathfinder, to, None) {
+            // Turn around, and head back the way we came.
+            unit.orders[0] = Order::Patrol { from: to, to: from };
+            unit.order_started = false;
+          }
+        }
+      }
+
+      Order::HoldPosition => {
+        let unit = &mut self.units[i];
+        unit.waypoints.clear();
+        if let Some(attack) = unit.unit_type.attack {
+          if let Some(enemy) = self.nearest_enemy(pos, owner, attack.range) {
+            self.attack(i, enemy);
+          }
+        }
+      }
+
+      Order::Stop => self.units[i].clear_orders(),
+
+      Order::Follow(target) => {
+        let target_pos = match self.locate(target, pos) {
+          Some((target_pos, _)) if target != uid => target_pos,
+          _ => {
+            self.units[i].finish_order();
+            return None;
+          }
+        };
+        let unit = &mut self.units[i];
+        if (target_pos - pos).magnitude() > FOLLOW_DISTANCE {
+          unit.chase(&self.map, &mut self.pathfinder, target_pos);
+        } else {
+          unit.waypoints.clear();
+        }
+      }
+
+      Order::CastAbility { ability, target } => {
+        let unit = &mut self.units[i];
+        let in_range = match ability.range() {
+          Some(range) => (target - pos).magnitude() <= range,
+          None => true,
+        };
+        if in_range {
+          unit.finish_order();
+          return Some((ability, target));
+        }
+        if unit.walk_to(&self.map, &mut self.pathfinder, target, None) {
+          // We've walked as far as we can.
+          println!("couldn't cast {}: target out of range", ability.name());
+          unit.finish_order();
+        }
+      }
+
+      Order::Gather(node) => self.update_gather(i, node),
+
+      Order::Build(site) => {
+        let site = self
+          .entities
+          .building(site)
+          .and_then(|j| self.buildings.get_mut(j))
+          .filter(|b| b.owner == owner && b.under_construction());
+        let unit = &mut self.units[i];
+        match site {
+          None => unit.finish_order(),
+          Some(site) if site.bounds().distance_to(pos) > unit.rad() + REACH => {
+            unit.chase(&self.map, &mut self.pathfinder, site.center());
+          }
+          Some(site) => {
+            unit.waypoints.clear();
+            site.construction_left.ticks -= 1;
+            if !site.under_construction() {
+              unit.finish_order();
+            }
+          }
+        }
+      }
+    }
+    None
+  }
+
+  // Gathering alternates between harvesting from the node until the unit is
+  // carrying something, and walking it back to the nearest drop-off building.
+  fn update_gather(&mut self, i: usize, node: UID) {
+    let (pos, owner, carrying) = {
+      let unit = &self.units[i];
+      (unit.pos, unit.owner, unit.carrying)
+    };
+    let reach = self.units[i].rad() + REACH;
+
+    if carrying > 0 {
+      let dropoff = self
+        .buildings
+        .iter()
+        .filter(|b| b.owner == owner && !b.under_construction())
+        .map(|b| (b.bounds().distance_to(pos), b.center()))
+        .min_by(|(d1, _), (d2, _)| d1.partial_cmp(d2).unwrap_or(Ordering::Equal));
+      let unit = &mut self.units[i];
+      match dropoff {
+        // Nowhere to take it. Wait until somewhere is built.
+        None => unit.waypoints.clear(),
+        Some((distance, center)) if distance > reach => {
+          unit.chase(&self.map, &mut self.pathfinder, center)
+        }
+        Some(_) => {
+          unit.waypoints.clear();
+          unit.carrying = 0;
+          if let Some(player) = self.players.get_mut(owner as usize) {
+            player.resources += carrying;
+          }
+        }
+      }
+      return;
+    }
+
+    let node = match self
+      .entities
+      .resource_node(node)
+      .and_then(|j| self.resource_nodes.get_mut(j))
+    {
+      Some(node) => node,
+      None => {
+        self.units[i].finish_order();
+        return;
+      }
+    };
+    let unit = &mut self.units[i];
+    if node.bounds().distance_to(pos) > reach {
+      unit.chase(
+        &self.map,
+        &mut self.pathfinder,
+        node.pos.center_to_world_point(),
+      );
+      return;
+    }
+    unit.waypoints.clear();
+    unit.gather_progress.ticks += 1;
+    if unit.gather_progress.ticks >= GameDur::from_secs(GATHER_SECS).ticks {
+      unit.gather_progress.ticks = 0;
+      let amount = u32::min(GATHER_AMOUNT, node.amount);
+      node.amount -= amount;
+      unit.carrying = amount;
+    }
+  }
+
+  // If there's an enemy nearby, makes the unit at index i interrupt its
+  // current order to go attack it. Returns true if it did.
+  fn engage_enemies(&mut self, i: usize) -> bool {
+    let unit = &self.units[i];
+    if unit.unit_type.attack.is_none() {
+      return false;
+    }
+    match self.nearest_enemy(unit.pos, unit.owner, ACQUIRE_RANGE) {
+      Some(enemy) => {
+        self.units[i].interrupt(Order::Attack(enemy));
+        true
+      }
+      None => false,
+    }
+  }
+
+  // The UID of the closest unit or building belonging to an enemy of owner,
+  // if there is one within max_distance of p that can be seen from there.
+  fn nearest_enemy(&self, p: Point, owner: PlayerId, max_distance: Coord) -> Option<UID> {
+    let unit = self
+      .unit_index
+      .nearest(p, max_distance, |(_, enemy), pos| {
+        enemy != owner && self.map.line_of_sight(p, pos)
+      })
+      .map(|((uid, _), distance)| (uid, distance));
+    let buildings = self
+      .buildings
+      .iter()
+      .filter(|b| b.owner != owner)
+      .map(|b| (b.uid, b.center(), b.bounds().distance_to(p)))
+      .filter(|(_, pos, distance)| *distance <= max_distance && self.map.line_of_sight(p, *pos))
+      .map(|(uid, _, distance)| (uid, distance));
+    unit
+      .into_iter()
+      .chain(buildings)
+      .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap_or(Ordering::Equal))
+      .map(|(uid, _)| uid)
+  }
+
+  // Makes the unit at index i hit the target, if its attack is ready.
+  fn attack(&mut self, i: usize, target: UID) {
+    let unit = &mut self.units[i];
+    let attack = match unit.unit_type.attack {
+      Some(attack) if unit.attack_cooldown.ticks == 0 => attack,
+      _ => return,
+    };
+    unit.attack_cooldown = attack.cooldown;
+    if let Some(target) = self.get_unit(target) {
+      target.hp = target.hp.saturating_sub(attack.damage);
+    } else if let Some(target) = self.get_building(target) {
+      target.hp = target.hp.saturating_sub(attack.damage);
+    }
+  }
+
+  // Finds the unit, building, or resource node with this UID. Returns its
+  // position, and the distance from p to its edge.
+  fn locate(&self, uid: UID, p: Point) -> Option<(Point, Coord)> {
+    if let Some(unit) = self.unit(uid) {
+      return Some((unit.pos, (unit.pos - p).magnitude() - unit.rad()));
+    }
+    if let Some(building) = self.building(uid) {
+      return Some((building.center(), building.bounds().distance_to(p)));
+    }
+    self.resource_node(uid).map(|node| {
+      (
+        node.pos.center_to_world_point(),
+        node.bounds().distance_to(p),
+      )
+    })
+  }
+
+  // The order that right-clicking at p means for units owned by owner:
+  // attack enemies, follow friendly units, help construct unfinished
+  // buildings, gather from resources, or otherwise just move there.
+  pub fn smart_order(&self, owner: PlayerId, p: Point) -> Order {
+    // Enemies hidden in the fog can't be picked out.
+    let hidden = |enemy: PlayerId| enemy != owner && !self.can_see(owner, p);
+    if let Some((uid, unit_owner)) = self
+      .unit_index
+      .within(p, Coord::ZERO)
+      .into_iter()
+      .find(|(_, unit_owner)| !hidden(*unit_owner))
+    {
+      return if unit_owner == owner {
+        Order::Follow(uid)
+      } else {
+        Order::Attack(uid)
+      };
+    }
+    if let Some(building) = self
+      .buildings
+      .iter()
+      .find(|b| b.bounds().contains(p) && !hidden(b.owner))
+    {
+      if building.owner != owner {
+        return Order::Attack(building.uid);
+      } else if building.under_construction() {
+        return Order::Build(building.uid);
+      }
+    }
+    if let Some(node) = self.resource_nodes.iter().find(|r| r.bounds().contains(p)) {
+      return Order::Gather(node.uid);
+    }
+    // Clicking on a wall means going to the nearest place next to it.
+    let tile = p.to_tile_point();
+    if self.map.get_tile_at(p).is_some() && !self.map.is_walkable(tile) {
+      if let Some(open) = self.map.nearest_walkable(tile) {
+        return Order::Move(open.tile_center());
+      }
+    }
+    Order::Move(p)
+  }
+
+  // Orders a group of units to move to dest together. Each unit gets its own
+  // spot around dest so they don't all fight over the same point: a group
+  // that's already close together keeps its layout, and a spread-out one is
+  // packed into a square. They all move at the speed of the slowest unit. If
+  // queue is true, the units move after finishing their other orders.
+  pub fn order_group_move(&mut self, uids: &[UID], dest: Point, queue: bool) {
+    // Where each unit will be when it starts this move.
+    let starts: Vec<(UID, Point)> = self
+      .units
+      .iter()
+      .filter(|u| uids.contains(&u.uid))
+      .map(|u| {
+        let queued_pos = u.orders.back().and_then(|o| o.target(self));
+        let start = if queue { queued_pos } else { None };
+        (u.uid, start.unwrap_or(u.pos))
+      })
+      .collect();
+    if starts.len() <= 1 {
+      for unit in self.units.iter_mut().filter(|u| uids.contains(&u.uid)) {
+        if !queue {
+          unit.clear_orders();
+        }
+        unit.queue_move(dest);
+      }
+      return;
+    }
+
+    let group = self.units.iter().filter(|u| uids.contains(&u.uid));
+    let speed = group
+      .clone()
+      .map(|u| u.unit_type.base_speed)
+      .min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+      .unwrap();
+    let max_rad = group
+      .map(|u| u.rad())
+      .max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+      .unwrap();
+
+    let n = Coord::from_int(starts.len() as i64);
+    let centroid = starts
+      .iter()
+      .fold(Point::new(Coord::ZERO, Coord::ZERO), |sum, (_, p)| sum + *p)
+      / n;
+    let spread = starts
+      .iter()
+      .map(|(_, p)| (*p - centroid).magnitude())
+      .max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+      .unwrap();
+    let slots: Vec<(UID, Point)> = if spread <= FORMATION_MAX_SPREAD {
+      starts
+        .iter()
+        .map(|(uid, p)| (*uid, dest + (*p - centroid)))
+        .collect()
+    } else {
+      compact_formation(&starts, dest, max_rad * Coord::from_int(2) + FORMATION_GAP)
+    };
+
+    for (uid, slot) in slots {
+      let map = &self.map;
+      if let Some(unit) = self.entities.unit(uid).and_then(|i| self.units.get_mut(i)) {
+        // Don't send anyone into a wall, or somewhere cut off from the rest
+        // of the group, just to keep the formation.
+        let slot_ok =
+          unit.fits_at(map, slot) && map.is_reachable(slot.to_tile_point(), dest.to_tile_point());
+        let slot = if slot_ok { slot } else { dest };
+        if !queue {
+          unit.clear_orders();
+        }
+        unit.queue_order(Order::FormationMove {
+          dest: slot,
+          group_dest: dest,
+          speed,
+        });
+      }
+    }
+  }
+
+  pub fn make_unit(&mut self, unit_type: UnitType, pos: Point, owner: PlayerId) {
+    let uid = self.entities.add(EntityRef::Unit(self.units.len()));
+    self.unit_index.insert((uid, owner), pos, unit_type.radius);
+    let energy = unit_type
+      .max_energy
+      .map(|max| EnergyPool::new(max, unit_type.energy_regen_per_sec));
     self.units.push(Unit {
       uid,
+      owner,
       pos,
+      hp: unit_type.max_hp,
       unit_type,
       selected: false,
+      orders: VecDeque::new(),
+      order_started: false,
       waypoints: VecDeque::new(),
       // TODO: Make settable by unit type
-      abilities: vec![AbilityBuild::new(uid, self.building_types[0].clone())],
+      abilities: vec![
+        AbilityAttackMove::new(uid),
+        AbilityStop::new(uid),
+        AbilityHold::new(uid),
+        AbilityPatrol::new(uid),
+        AbilityBuild::new(uid, self.building_types[0].clone()),
+        AbilityHeal::new(uid),
+      ],
+      cooldowns: Cooldowns::new(),
+      energy,
+      attack_cooldown: GameDur { ticks: 0 },
+      carrying: 0,
+      gather_progress: GameDur { ticks: 0 },
     });
   }
 
-  fn get_unit(&mut self, uid: UID) -> Option<&mut Unit> {
-    self.units.iter_mut().find(|u| u.uid == uid)
+  pub fn get_unit(&mut self, uid: UID) -> Option<&mut Unit> {
+    self.entities.unit(uid).and_then(|i| self.units.get_mut(i))
+  }
+
+  pub fn unit(&self, uid: UID) -> Option<&Unit> {
+    self.entities.unit(uid).and_then(|i| self.units.get(i))
+  }
+
+  // Checks that the caster can pay for a cast of the named ability: it must be
+  // off cooldown and have at least energy_cost energy.
+  pub fn check_can_cast(
+    &mut self,
+    caster: UID,
+    ability_name: &'static str,
+    energy_cost: u32,
+  ) -> Result<(), CastError> {
+    if let Some(building) = self.building(caster) {
+      if building.under_construction() {
+        return Err(CastError::UnderConstruction);
+      }
+    }
+    let (cooldowns, energy) = self
+      .caster_resources(caster)
+      .ok_or(CastError::CasterNotFound)?;
+    if let Some(left) = cooldowns.remaining(ability_name) {
+      return Err(CastError::OnCooldown(left));
+    }
+    if energy_cost > 0 {
+      let available = energy.map(|e| e.current).unwrap_or(0);
+      if available < energy_cost {
+        return Err(CastError::NotEnoughEnergy {
+          needed: energy_cost,
+          available,
+        });
+      }
+    }
+    Ok(())
+  }
+
+  // Puts the named ability on cooldown and drains its energy cost from the
+  // caster. Should only be called after check_can_cast succeeds.
+  pub fn pay_for_cast(
+    &mut self,
+    caster: UID,
+    ability_name: &'static str,
+    cooldown: GameDur,
+    energy_cost: u32,
+  ) {
+    if let Some((cooldowns, energy)) = self.caster_resources(caster) {
+      cooldowns.start(ability_name, cooldown);
+      if let Some(energy) = energy {
+        energy.current = energy.current.saturating_sub(energy_cost);
+      }
+    }
+  }
+
+  // Orders the caster of a point-targeted ability to cast it at the target.
+  // Units walk into range first, and if queue is true they finish their other
+  // orders before starting this one. Buildings cast immediately.
+  pub fn order_cast(
+    &mut self,
+    ability: Rc<dyn PointTargetedAbility>,
+    target: Point,
+    queue: bool,
+  ) -> Result<(), CastError> {
+    let caster = ability.caster();
+    if self.unit(caster).is_none() {
+      return ability.cast(self, target);
+    }
+
+    // Queued casts are checked when they're made, since the caster may be
+    // ready by the time it gets to them.
+    if !queue {
+      self.check_can_cast(caster, ability.name(), ability.energy_cost())?;
+    }
+    let unit = self.get_unit(caster).ok_or(CastError::CasterNotFound)?;
+    if !queue {
+      unit.clear_orders();
+    }
+    unit
+      .orders
+      .push_back(Order::CastAbility { ability, target });
+    Ok(())
+  }
+
+  // A rough measure of how much work the unit or building with this UID has
+  // queued up: orders left for units, ticks of training left for buildings.
+  // An idle caster has busyness 0.
+  pub fn busyness(&self, uid: UID) -> Option<u32> {
+    if let Some(unit) = self.unit(uid) {
+      return Some(unit.orders.len() as u32);
+    }
+    self.building(uid).map(|building| {
+      building
+        .train_queue
+        .iter()
+        .map(|training| training.dur_left.ticks)
+        .sum()
+    })
+  }
+
+  // The position of the unit, or the center of the building or resource
+  // node, with this UID.
+  pub fn entity_pos(&self, uid: UID) -> Option<Point> {
+    self
+      .locate(uid, Point::new(Coord::ZERO, Coord::ZERO))
+      .map(|(pos, _)| pos)
+  }
+
+  pub fn owner(&self, uid: UID) -> Option<PlayerId> {
+    if let Some(unit) = self.unit(uid) {
+      return Some(unit.owner);
+    }
+    self.building(uid).map(|building| building.owner)
   }
 
-  pub fn make_building(&mut self, building_type: BuildingType, top_left_pos: TilePoint) {
-    let uid = self.next_uid();
+  // Finds the cooldowns and energy pool of the unit or building with this UID.
+  fn caster_resources(&mut self, uid: UID) -> Option<(&mut Cooldowns, Option<&mut EnergyPool>)> {
+    if let Some(i) = self.entities.unit(uid) {
+      let unit = self.units.get_mut(i)?;
+      return Some((&mut unit.cooldowns, unit.energy.as_mut()));
+    }
+    self
+      .get_building(uid)
+      .map(|building| (&mut building.cooldowns, None))
+  }
+
+  // Makes a finished building.
+  pub fn make_building(
+    &mut self,
+    building_type: BuildingType,
+    top_left_pos: TilePoint,
+    owner: PlayerId,
+  ) -> UID {
+    let uid = self.entities.add(EntityRef::Building(self.buildings.len()));
+    let (width, height) = (building_type.width, building_type.height);
+    self.map.occupy(top_left_pos, width, height);
+    self
+      .pathfinder
+      .update(&self.map, top_left_pos, width, height);
     self.buildings.push(Building {
       uid,
+      owner,
 
       top_left_pos,
+      hp: building_type.max_hp,
+      construction_left: GameDur { ticks: 0 },
       building_type,
 
       selected: false,
@@ -159,29 +987,123 @@ impl State {
         // a text file and validate them on load.
         self.unit_types[0].clone(),
       )],
+      cooldowns: Cooldowns::new(),
     });
+    uid
+  }
+
+  // Makes a building which workers need to finish constructing before it can
+  // be used.
+  pub fn start_construction(
+    &mut self,
+    building_type: BuildingType,
+    top_left_pos: TilePoint,
+    owner: PlayerId,
+  ) -> UID {
+    let build_time = building_type.build_time;
+    let uid = self.make_building(building_type, top_left_pos, owner);
+    if let Some(building) = self.get_building(uid) {
+      building.construction_left = build_time;
+    }
+    uid
+  }
+
+  pub fn make_resource_node(&mut self, pos: TilePoint, amount: u32) -> UID {
+    let uid = self
+      .entities
+      .add(EntityRef::ResourceNode(self.resource_nodes.len()));
+    self.resource_nodes.push(ResourceNode { uid, pos, amount });
+    uid
   }
 
   pub fn get_building(&mut self, uid: UID) -> Option<&mut Building> {
-    self.buildings.iter_mut().find(|b| b.uid == uid)
+    self
+      .entities
+      .building(uid)
+      .and_then(|i| self.buildings.get_mut(i))
+  }
+
+  pub fn building(&self, uid: UID) -> Option<&Building> {
+    self
+      .entities
+      .building(uid)
+      .and_then(|i| self.buildings.get(i))
+  }
+
+  pub fn resource_node(&self, uid: UID) -> Option<&ResourceNode> {
+    self
+      .entities
+      .resource_node(uid)
+      .and_then(|i| self.resource_nodes.get(i))
   }
 }
 
+// Lays out units in a square grid centered on dest, spacing apart the centers
+// of neighbouring units. Units are assigned spots in the order they're
+// already in from top to bottom, left to right, so that their paths don't
+// cross too much.
+fn compact_formation(starts: &[(UID, Point)], dest: Point, spacing: Coord) -> Vec<(UID, Point)> {
+  let mut starts = starts.to_vec();
+  starts.sort_by(|(_, a), (_, b)| {
+    (a.y, a.x)
+      .partial_cmp(&(b.y, b.x))
+      .unwrap_or(Ordering::Equal)
+  });
+  // The square root of how many there are, rounded up.
+  let cols = (starts.len().max(1) - 1).isqrt() + 1;
+  let rows = starts.len().div_ceil(cols);
+  let top_left = dest
+    - Point::new(
+      spacing * Coord::from_int(cols as i64 - 1),
+      spacing * Coord::from_int(rows as i64 - 1),
+    ) / Coord::from_int(2);
+  starts
+    .iter()
+    .enumerate()
+    .map(|(i, (uid, _))| {
+      let (row, col) = (i / cols, i % cols);
+      let offset = Point::new(
+        spacing * Coord::from_int(col as i64),
+        spacing * Coord::from_int(row as i64),
+      );
+      (*uid, top_left + offset)
+    })
+    .collect()
+}
+
 pub struct Unit {
   pub uid: UID,
+  pub owner: PlayerId,
   pub pos: Point,
+  pub hp: u32,
   pub unit_type: UnitType,
   pub selected: bool,
+  // Orders the unit will carry out, in order. The front one is in progress.
+  pub orders: VecDeque<Order>,
+  // Whether the unit has started moving to carry out its current order.
+  order_started: bool,
+  // The path the unit is following to carry out its current order.
   pub waypoints: VecDeque<Point>,
   pub abilities: Vec<Ability>,
+  pub cooldowns: Cooldowns,
+  pub energy: Option<EnergyPool>,
+  // Time until the unit can attack again.
+  pub attack_cooldown: GameDur,
+  // Resources the unit is taking back to a drop-off.
+  pub carrying: u32,
+  // Time spent so far harvesting the current load of resources.
+  gather_progress: GameDur,
 }
 
 impl Unit {
   fn speed(&self) -> Coord {
-    self.unit_type.base_speed
+    match self.orders.front() {
+      Some(Order::FormationMove { speed, .. }) if *speed < self.unit_type.base_speed => *speed,
+      _ => self.unit_type.base_speed,
+    }
   }
 
-  fn rad(&self) -> Coord {
+  pub fn rad(&self) -> Coord {
     self.unit_type.radius
   }
 
@@ -192,99 +1114,266 @@ impl Unit {
     let top_left = p - Point::new(self.rad(), self.rad());
     Rect {
       top_left,
-      width: self.rad() * Coord(2.),
-      height: self.rad() * Coord(2.),
+      width: self.rad() * Coord::from_int(2),
+      height: self.rad() * Coord::from_int(2),
     }
   }
 
   pub fn queue_move(&mut self, p: Point) {
-    self.waypoints.push_back(p);
+    self.queue_order(Order::Move(p));
+  }
+
+  pub fn queue_order(&mut self, order: Order) {
+    self.orders.push_back(order);
   }
 
   pub fn move_queued(&self) -> bool {
-    self.waypoints.len() > 0
+    !self.orders.is_empty()
   }
 
-  // Find a path to dest, and enqueue that path in the waypoints. Returns true
-  // if a path was found, and false otherwise.
-  //
-  // As a first pass, this is implemented as BFS.
-  // TODO: Implement A*.
-  pub fn pathfind(&mut self, map: &Map, dest: Point) -> bool {
+  // Drops all of the unit's orders, and stops it where it is.
+  pub fn clear_orders(&mut self) {
+    self.orders.clear();
+    self.order_started = false;
+    self.waypoints.clear();
+  }
+
+  fn finish_order(&mut self) {
+    self.orders.pop_front();
+    self.order_started = false;
+    self.waypoints.clear();
+  }
+
+  // Puts an order in front of the unit's current one. Once it's done, the
+  // unit goes back to what it was doing.
+  pub fn interrupt(&mut self, order: Order) {
+    self.orders.push_front(order);
+    self.order_started = false;
+    self.waypoints.clear();
+  }
+
+  // Moves the unit towards dest, finding a path the first time it's called for
+  // an order. Returns true once the unit has gotten as close as it can. Units
+  // moving as a group pass the group's destination as via; see
+  // Pathfinder::find_path.
+  fn walk_to(
+    &mut self,
+    map: &Map,
+    pathfinder: &mut Pathfinder,
+    dest: Point,
+    via: Option<Point>,
+  ) -> bool {
+    if !self.waypoints.is_empty() {
+      return false;
+    }
+    if self.order_started {
+      return true;
+    }
+    self.order_started = true;
+    let found_path = self.pathfind(map, pathfinder, dest, via);
+    if !found_path {
+      self.waypoints.push_back(dest);
+    }
+    false
+  }
+
+  // Moves the unit towards dest, which may be different every time it's
+  // called. Finds a new path whenever dest moves to another tile.
+  fn chase(&mut self, map: &Map, pathfinder: &mut Pathfinder, dest: Point) {
+    let dest_tile = dest.to_tile_point();
+    if self.pos.to_tile_point() == dest_tile {
+      // Close enough to walk straight there.
+      self.waypoints.clear();
+      self.waypoints.push_back(dest);
+      return;
+    }
+    let heading_to = self.waypoints.back().map(|p| p.to_tile_point());
+    if heading_to != Some(dest_tile) {
+      self.waypoints.clear();
+      let found_path = self.pathfind(map, pathfinder, dest, None);
+      if !found_path {
+        self.waypoints.push_back(dest);
+      }
+    }
+  }
+
+  // Find a path to dest, and enqueue that path in the waypoints. If dest can't
+  // be reached, the path goes as close as it can. Returns true if a path was
+  // found, and false otherwise.
+  pub fn pathfind(
+    &mut self,
+    map: &Map,
+    pathfinder: &mut Pathfinder,
+    dest: Point,
+    via: Option<Point>,
+  ) -> bool {
     let src = if self.waypoints.is_empty() {
       self.pos
     } else {
       self.waypoints[self.waypoints.len() - 1]
     };
-    let src = src.to_tile_point();
-    let dest = dest.to_tile_point();
-
-    #[derive(Clone, Copy)]
-    struct BackPath {
-      here: TilePoint,
-      best_source: TilePoint,
-      path_cost: u32,
-    }
-
-    // Find a path. (incidentally, finds the best path)
-    let mut visited: HashMap<TilePoint, BackPath> = HashMap::new();
-    let mut to_visit: VecDeque<BackPath> = VecDeque::new();
-    to_visit.push_front(BackPath {
-      here: src,
-      best_source: src, // Just need to put some value here.
-      path_cost: 0,
-    });
-    while !to_visit.is_empty() {
-      let point = to_visit.pop_front().unwrap();
-      if visited.contains_key(&point.here) {
-        continue;
+    let dest_tile = dest.to_tile_point();
+    let path = pathfinder.find_path(
+      map,
+      src.to_tile_point(),
+      dest_tile,
+      via.map(|p| p.to_tile_point()),
+      clearance_needed(self.rad()),
+    );
+    let path = match path {
+      Some(path) => path,
+      None => return false,
+    };
+
+    // Make waypoints for the path found.
+    let reaches_dest = path.last() == Some(&dest_tile);
+    let mut points = vec![src];
+    points.extend(path.iter().map(|p| p.tile_center()));
+    // Finish at the exact destination rather than the middle of its tile, as
+    // long as we can get there and won't bump into anything.
+    if reaches_dest && self.fits_at(map, dest) {
+      points.pop();
+      points.push(dest);
+    }
+    let points = self.cut_corners(map, points);
+    self.waypoints.extend(points.into_iter().skip(1));
+    true
+  }
+
+  // Takes a path through the middles of tiles and cuts across wherever the
+  // unit could walk straight, so it doesn't zigzag along the grid. Only the
+  // points where the unit needs to turn are kept. This is the same shortcut
+  // Theta* takes while searching, but done afterwards along the path we found.
+  //
+  // The path may go the long way round to stay on fast ground, so we only cut
+  // across when it's no slower than following the path.
+  fn cut_corners(&self, map: &Map, points: Vec<Point>) -> Vec<Point> {
+    let mut out = vec![points[0]];
+    // The cost of following the path from the last point kept.
+    let mut path_cost = Coord::ZERO;
+    for i in 1..points.len() - 1 {
+      let (from, to) = (*out.last().unwrap(), points[i + 1]);
+      path_cost += map.move_cost_along(points[i], to);
+      if i == 1 {
+        path_cost += map.move_cost_along(from, points[i]);
       }
-      visited.insert(point.here, point);
-      if point.here == dest {
-        break;
-      }
-      for p in point.here.neighbors4(&map) {
-        let tile_blocked = map
-          .get_tile(p)
-          .map(|t| t != GridTile::Empty)
-          .unwrap_or(true);
-        if tile_blocked {
-          continue;
-        }
-        to_visit.push_back(BackPath {
-          here: p,
-          best_source: point.here,
-          path_cost: point.path_cost + 1,
-        });
+      let shortcut_ok = map.can_walk_straight(from, to, self.rad())
+        && map.move_cost_along(from, to) <= path_cost + path_cost / Coord::from_int(1000);
+      if !shortcut_ok {
+        out.push(points[i]);
+        path_cost = map.move_cost_along(points[i], to);
       }
     }
-    if !visited.contains_key(&dest) {
-      return false;
+    if points.len() > 1 {
+      out.push(points[points.len() - 1]);
     }
+    out
+  }
 
-    // Make waypoints for the path found.
-    let mut path_reverse = vec![];
-    let mut current = visited.get(&dest).unwrap();
-    while current.here != src {
-      path_reverse.push(current.here);
-      current = visited.get(&current.best_source).unwrap();
-    }
-    self.waypoints.push_back(src.tile_center());
-    for p in path_reverse.iter().rev() {
-      self.waypoints.push_back(p.tile_center());
-    }
-    true
+  // Whether the unit could stand at p without overlapping any obstacles.
+  pub fn fits_at(&self, map: &Map, p: Point) -> bool {
+    map.get_tile_at(p).is_some()
+      && map
+        .tiles_overlapping_rect(self.bounding_box_at(p))
+        .all(|item| map.is_walkable(item.pos))
   }
 
   pub fn window_rad(&self) -> u32 {
-    self.rad().0 as u32
+    self.rad().to_f32() as u32
+  }
+}
+
+// An instruction given to a unit by the player.
+#[derive(Clone)]
+pub enum Order {
+  Move(Point),
+  // Move as part of a group, no faster than the group's slowest unit. dest is
+  // this unit's spot in the formation, and group_dest is where the group as a
+  // whole is headed.
+  FormationMove {
+    dest: Point,
+    group_dest: Point,
+    speed: Coord,
+  },
+  // Move to the point, attacking enemies met on the way.
+  AttackMove(Point),
+  Attack(UID),
+  // Walk back and forth between two points, attacking enemies met on the way.
+  Patrol {
+    from: Point,
+    to: Point,
+  },
+  // Stay put, attacking enemies that come into range.
+  HoldPosition,
+  // Stop, dropping any orders queued after this one.
+  Stop,
+  Follow(UID),
+  CastAbility {
+    ability: Rc<dyn PointTargetedAbility>,
+    target: Point,
+  },
+  // Harvest from a resource node, and carry it back to a drop-off building.
+  Gather(UID),
+  // Help construct an unfinished building.
+  Build(UID),
+}
+
+impl Order {
+  // What the order is, for snapshots.
+  fn describe(&self) -> String {
+    let point = |p: &Point| format!("({}, {})", p.x, p.y);
+    match self {
+      Order::Move(p) => format!("move {}", point(p)),
+      Order::FormationMove {
+        dest,
+        group_dest,
+        speed,
+      } => format!(
+        "formation move {} of {} at {}",
+        point(dest),
+        point(group_dest),
+        speed
+      ),
+      Order::AttackMove(p) => format!("attack-move {}", point(p)),
+      Order::Attack(uid) => format!("attack {}", uid),
+      Order::Patrol { from, to } => format!("patrol {} {}", point(from), point(to)),
+      Order::HoldPosition => "hold position".to_string(),
+      Order::Stop => "stop".to_string(),
+      Order::Follow(uid) => format!("follow {}", uid),
+      Order::CastAbility { ability, target } => format!(
+        "cast {} by {} at {}",
+        ability.name(),
+        ability.caster(),
+        point(target)
+      ),
+      Order::Gather(uid) => format!("gather {}", uid),
+      Order::Build(uid) => format!("build {}", uid),
+    }
+  }
+
+  // Where the unit needs to go to carry out the order, if anywhere.
+  pub fn target(&self, state: &State) -> Option<Point> {
+    match self {
+      Order::Move(p) | Order::AttackMove(p) => Some(*p),
+      Order::FormationMove { dest, .. } => Some(*dest),
+      Order::Patrol { to, .. } => Some(*to),
+      Order::CastAbility { target, .. } => Some(*target),
+      Order::Attack(uid) | Order::Follow(uid) | Order::Gather(uid) | Order::Build(uid) => {
+        state.entity_pos(*uid)
+      }
+      Order::HoldPosition | Order::Stop => None,
+    }
   }
 }
 
 pub struct Building {
   pub uid: UID,
+  pub owner: PlayerId,
 
   pub top_left_pos: TilePoint,
+  pub hp: u32,
+  // Work left before the building is finished. Zero once it's usable.
+  pub construction_left: GameDur,
   pub building_type: BuildingType,
 
   pub selected: bool,
@@ -293,6 +1382,7 @@ pub struct Building {
   pub train_queue_max_len: usize,
 
   pub abilities: Vec<Ability>,
+  pub cooldowns: Cooldowns,
 }
 
 impl Building {
@@ -305,12 +1395,62 @@ impl Building {
     self.building_type.height
   }
 
+  pub fn under_construction(&self) -> bool {
+    self.construction_left.ticks > 0
+  }
+
+  pub fn bounds(&self) -> Rect {
+    Rect {
+      top_left: self.top_left_pos.to_world_point(),
+      width: Coord::from_int(self.width() as i64) * TILE_WIDTH_COORD,
+      height: Coord::from_int(self.height() as i64) * TILE_WIDTH_COORD,
+    }
+  }
+
+  pub fn center(&self) -> Point {
+    let top_left = self.top_left_pos.to_world_point();
+    let half_dim =
+      TilePoint::new(self.width(), self.height()).to_world_point() / Coord::from_int(2);
+    top_left + half_dim
+  }
+
   fn spawn_location(&self) -> Point {
     let tile_pos = self.top_left_pos + TilePoint::new(0, self.height());
     tile_pos.center_to_world_point()
   }
 }
 
+// The types of unit there are in every game.
+pub fn unit_types() -> Vec<UnitType> {
+  vec![UnitType {
+    name: "Newt",
+    sprite_key: "newt_gingrich".to_string(),
+    radius: Coord::from_int(16),
+    base_speed: Coord::from_int(1),
+    max_energy: Some(100),
+    energy_regen_per_sec: 2,
+    max_hp: 40,
+    sight: 6,
+    attack: Some(AttackStats {
+      damage: 5,
+      range: Coord::from_int(8),
+      cooldown: GameDur::from_secs(1),
+    }),
+  }]
+}
+
+// The types of building there are in every game.
+pub fn building_types() -> Vec<BuildingType> {
+  vec![BuildingType {
+    name: "Town Hall",
+    width: 1,
+    height: 1,
+    max_hp: 500,
+    build_time: GameDur::from_secs(10),
+    sight: 8,
+  }]
+}
+
 // Sort of a factory for units. Stores some properties of the unit so that one
 // can make more of a type without closures.
 //
@@ -323,6 +1463,23 @@ pub struct UnitType {
   pub sprite_key: SpriteKey,
   pub radius: Coord,
   pub base_speed: Coord,
+  // Units without max_energy have no energy pool, and can't cast abilities
+  // that cost energy.
+  pub max_energy: Option<u32>,
+  pub energy_regen_per_sec: u32,
+  pub max_hp: u32,
+  // How far the unit can see, in tiles.
+  pub sight: u32,
+  // Units without an attack can't fight back.
+  pub attack: Option<AttackStats>,
+}
+
+#[derive(Clone, Copy)]
+pub struct AttackStats {
+  pub damage: u32,
+  // Measured from the attacker's center to the edge of its target.
+  pub range: Coord,
+  pub cooldown: GameDur,
 }
 
 #[derive(Clone)]
@@ -333,6 +1490,34 @@ pub struct BuildingType {
   // Width and height measured in tiles.
   pub width: u32,
   pub height: u32,
+  pub max_hp: u32,
+  pub build_time: GameDur,
+  // How far the building can see, in tiles.
+  pub sight: u32,
+}
+
+pub struct Player {
+  // Resources gathered and not yet spent.
+  pub resources: u32,
+  // What of the map the player can see.
+  pub vision: Vision,
+}
+
+// A one-tile patch of resources which workers can gather from.
+pub struct ResourceNode {
+  pub uid: UID,
+  pub pos: TilePoint,
+  pub amount: u32,
+}
+
+impl ResourceNode {
+  pub fn bounds(&self) -> Rect {
+    Rect {
+      top_left: self.pos.to_world_point(),
+      width: TILE_WIDTH_COORD,
+      height: TILE_WIDTH_COORD,
+    }
+  }
 }
 
 pub struct UnitTraining {
@@ -340,3 +1525,80 @@ pub struct UnitTraining {
   pub dur_total: GameDur,
   pub dur_left: GameDur,
 }
+
+// Time left before each of a caster's abilities can be cast again, keyed by
+// ability name. Abilities that are ready to cast have no entry.
+pub struct Cooldowns {
+  remaining: HashMap<&'static str, GameDur>,
+}
+
+impl Cooldowns {
+  pub fn new() -> Cooldowns {
+    Cooldowns {
+      remaining: HashMap::new(),
+    }
+  }
+
+  pub fn remaining(&self, ability_name: &str) -> Option<GameDur> {
+    self.remaining.get(ability_name).copied()
+  }
+
+  pub fn start(&mut self, ability_name: &'static str, dur: GameDur) {
+    if dur.ticks > 0 {
+      self.remaining.insert(ability_name, dur);
+    }
+  }
+
+  // Each ability's time left, in order of name.
+  fn describe(&self) -> String {
+    let mut remaining: Vec<String> = self
+      .remaining
+      .iter()
+      .map(|(name, dur)| format!("{} {}", name, dur.ticks))
+      .collect();
+    remaining.sort();
+    format!("[{}]", remaining.join(", "))
+  }
+
+  fn tick(&mut self) {
+    for dur in self.remaining.values_mut() {
+      dur.ticks -= 1;
+    }
+    self.remaining.retain(|_, dur| dur.ticks > 0);
+  }
+}
+
+// A pool of energy which abilities can spend, and which refills over time.
+#[derive(Clone, Copy)]
+pub struct EnergyPool {
+  pub current: u32,
+  pub max: u32,
+  pub regen_per_sec: u32,
+
+  // Progress towards the next point of energy, counted so that it reaches
+  // TICKS_PER_SEC after 1/regen_per_sec seconds.
+  regen_progress: u32,
+}
+
+impl EnergyPool {
+  pub fn new(max: u32, regen_per_sec: u32) -> EnergyPool {
+    EnergyPool {
+      current: max,
+      max,
+      regen_per_sec,
+      regen_progress: 0,
+    }
+  }
+
+  fn tick(&mut self) {
+    if self.current >= self.max {
+      self.regen_progress = 0;
+      return;
+    }
+    self.regen_progress += self.regen_per_sec;
+    while self.regen_progress >= TICKS_PER_SEC && self.current < self.max {
+      self.regen_progress -= TICKS_PER_SEC;
+      self.current += 1;
+    }
+  }
+}
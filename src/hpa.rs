@@ -0,0 +1,350 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::map::{GridTile, Map, TilePoint};
+
+// Width and height of a cluster, in tiles.
+const CLUSTER_SIZE: u32 = 16;
+// Openings between clusters wider than this get an entrance at each end
+// rather than one in the middle, so paths don't detour through the middle of a
+// wide-open border.
+const MAX_ENTRANCE_WIDTH: u32 = 6;
+
+// A graph for hierarchical pathfinding (HPA*). The map is cut into square
+// clusters, and we work out ahead of time where the entrances between
+// neighbouring clusters are and how far apart the entrances of each cluster
+// are from each other. A long path is found by searching this much smaller
+// graph of entrances, and then filling in the steps within each cluster it
+// passes through. The path isn't always the very shortest, but it's close,
+// and finding it doesn't mean searching the whole map.
+pub struct PathGraph {
+  // The clearance units using this graph need; see Map::clearance. Units of
+  // different sizes fit through different gaps, so they each need their own
+  // graph.
+  pub clearance: u8,
+  clusters_wide: u32,
+  clusters_high: u32,
+  clusters: Vec<Cluster>,
+}
+
+struct Cluster {
+  bounds: TileRect,
+  // The edges out of each entrance tile in the cluster, and the cost of taking
+  // them. These go to the cluster's other entrances, and across the border to
+  // the matching entrance of the neighbouring cluster.
+  edges: HashMap<TilePoint, Vec<(TilePoint, u32)>>,
+}
+
+// The tiles from (left, top) up to, but not including, (right, bottom).
+#[derive(Clone, Copy)]
+struct TileRect {
+  left: u32,
+  top: u32,
+  right: u32,
+  bottom: u32,
+}
+
+impl TileRect {
+  fn contains(&self, p: TilePoint) -> bool {
+    self.left <= p.x() && p.x() < self.right && self.top <= p.y() && p.y() < self.bottom
+  }
+}
+
+impl PathGraph {
+  pub fn new(map: &Map, clearance: u8) -> PathGraph {
+    let clusters_wide = map.width.div_ceil(CLUSTER_SIZE);
+    let clusters_high = map.height.div_ceil(CLUSTER_SIZE);
+    let mut graph = PathGraph {
+      clearance,
+      clusters_wide,
+      clusters_high,
+      clusters: vec![],
+    };
+    for cy in 0..clusters_high {
+      for cx in 0..clusters_wide {
+        let bounds = TileRect {
+          left: cx * CLUSTER_SIZE,
+          top: cy * CLUSTER_SIZE,
+          right: u32::min((cx + 1) * CLUSTER_SIZE, map.width),
+          bottom: u32::min((cy + 1) * CLUSTER_SIZE, map.height),
+        };
+        graph.clusters.push(Cluster {
+          bounds,
+          edges: HashMap::new(),
+        });
+      }
+    }
+    for i in 0..graph.clusters.len() {
+      graph.build_cluster(map, i);
+    }
+    graph
+  }
+
+  // Rebuilds the parts of the graph that depend on the tiles in a rect, after
+  // they've become walkable or unwalkable. Only the clusters the rect touches
+  // and the neighbours they share a changed border with are redone.
+  pub fn update(&mut self, map: &Map, top_left: TilePoint, width: u32, height: u32) {
+    // The change can make tiles up to clearance - 1 away too tight for us to
+    // fit, and growing the rect by another tile catches the neighbours whose
+    // entrances changed.
+    let grow = self.clearance as u32;
+    let left = top_left.x().saturating_sub(grow) / CLUSTER_SIZE;
+    let top = top_left.y().saturating_sub(grow) / CLUSTER_SIZE;
+    let right = u32::min(
+      (top_left.x() + width + grow - 1) / CLUSTER_SIZE,
+      self.clusters_wide - 1,
+    );
+    let bottom = u32::min(
+      (top_left.y() + height + grow - 1) / CLUSTER_SIZE,
+      self.clusters_high - 1,
+    );
+    for cy in top..=bottom {
+      for cx in left..=right {
+        self.build_cluster(map, (cx + cy * self.clusters_wide) as usize);
+      }
+    }
+  }
+
+  fn cluster_index(&self, p: TilePoint) -> usize {
+    (p.x() / CLUSTER_SIZE + p.y() / CLUSTER_SIZE * self.clusters_wide) as usize
+  }
+
+  // Finds the entrances of a cluster, and the edges between them.
+  fn build_cluster(&mut self, map: &Map, i: usize) {
+    let bounds = self.clusters[i].bounds;
+    let mut edges: HashMap<TilePoint, Vec<(TilePoint, u32)>> = HashMap::new();
+    for (mine, theirs) in entrances(map, bounds, self.clearance) {
+      let cost = map.step_cost(mine, theirs);
+      edges.entry(mine).or_default().push((theirs, cost));
+    }
+    let entrances: Vec<TilePoint> = edges.keys().copied().collect();
+    for &from in entrances.iter() {
+      let reached = search(map, bounds, from, self.clearance);
+      for &to in entrances.iter() {
+        if to == from {
+          continue;
+        }
+        if let Some((_, cost)) = reached.get(&to) {
+          edges.get_mut(&from).unwrap().push((to, *cost));
+        }
+      }
+    }
+    self.clusters[i].edges = edges;
+  }
+
+  // Finds a path from src to dest. Returns every tile along the way, including
+  // both ends, or None if there's no way there.
+  //
+  // dest doesn't have to be walkable itself, so units can head for a building
+  // and stop when they bump into it.
+  pub fn find_path(&self, map: &Map, src: TilePoint, dest: TilePoint) -> Option<Vec<TilePoint>> {
+    if map.get_tile(src).is_none() || map.get_tile(dest).is_none() {
+      return None;
+    }
+    if !map.has_clearance(dest, self.clearance) {
+      // Get next to dest instead, starting with the side facing src.
+      let mut sides = dest.neighbors4(map);
+      sides.retain(|p| map.has_clearance(*p, self.clearance));
+      sides.sort_by_key(|p| p.x().abs_diff(src.x()) + p.y().abs_diff(src.y()));
+      return sides.into_iter().find_map(|side| {
+        let mut path = self.find_path(map, src, side)?;
+        path.push(dest);
+        Some(path)
+      });
+    }
+    let src_cluster = &self.clusters[self.cluster_index(src)];
+    let dest_cluster = &self.clusters[self.cluster_index(dest)];
+    let from_src = search(map, src_cluster.bounds, src, self.clearance);
+    if from_src.contains_key(&dest) {
+      return Some(trace(&from_src, dest));
+    }
+    let from_dest = search(map, dest_cluster.bounds, dest, self.clearance);
+
+    // A* over the entrances, starting from all the ones src can get to.
+    let heuristic = |p: TilePoint| {
+      let steps = p.x().abs_diff(dest.x()) + p.y().abs_diff(dest.y());
+      steps * 2 * GridTile::MIN_MOVE_COST
+    };
+    let mut best: HashMap<TilePoint, (u32, Option<TilePoint>)> = HashMap::new();
+    let mut to_visit = BinaryHeap::new();
+    for (&entrance, _) in src_cluster.edges.iter() {
+      if let Some((_, cost)) = from_src.get(&entrance) {
+        best.insert(entrance, (*cost, None));
+        to_visit.push(Reverse((cost + heuristic(entrance), *cost, entrance)));
+      }
+    }
+    // The cheapest way found so far into dest's cluster, and the entrance it
+    // goes through.
+    let mut goal: Option<(u32, TilePoint)> = None;
+    while let Some(Reverse((estimate, cost, here))) = to_visit.pop() {
+      if let Some((goal_cost, _)) = goal {
+        if estimate >= goal_cost {
+          break;
+        }
+      }
+      if cost > best[&here].0 {
+        continue;
+      }
+      if let Some((_, to_dest)) = from_dest.get(&here) {
+        if goal.is_none_or(|(goal_cost, _)| cost + to_dest < goal_cost) {
+          goal = Some((cost + to_dest, here));
+        }
+      }
+      let cluster = &self.clusters[self.cluster_index(here)];
+      for &(next, step) in cluster.edges.get(&here).into_iter().flatten() {
+        let next_cost = cost + step;
+        if best.get(&next).is_none_or(|(c, _)| next_cost < *c) {
+          best.insert(next, (next_cost, Some(here)));
+          to_visit.push(Reverse((next_cost + heuristic(next), next_cost, next)));
+        }
+      }
+    }
+    let (_, last) = goal?;
+
+    // Walk back through the entrances used, then fill in the steps between
+    // them.
+    let mut entrances = vec![last];
+    while let Some((_, Some(prev))) = best.get(entrances.last().unwrap()) {
+      entrances.push(*prev);
+    }
+    entrances.reverse();
+
+    let mut path = trace(&from_src, entrances[0]);
+    for pair in entrances.windows(2) {
+      let (from, to) = (pair[0], pair[1]);
+      let cluster = &self.clusters[self.cluster_index(from)];
+      if cluster.bounds.contains(to) {
+        let reached = search(map, cluster.bounds, from, self.clearance);
+        path.extend(trace(&reached, to).into_iter().skip(1));
+      } else {
+        path.push(to);
+      }
+    }
+    let mut to_dest = trace(&from_dest, last);
+    to_dest.reverse();
+    path.extend(to_dest.into_iter().skip(1));
+    Some(path)
+  }
+}
+
+// The pairs of tiles, one inside bounds and one just outside, which make up
+// the entrances between a cluster and its neighbours.
+fn entrances(map: &Map, bounds: TileRect, clearance: u8) -> Vec<(TilePoint, TilePoint)> {
+  let TileRect {
+    left,
+    top,
+    right,
+    bottom,
+  } = bounds;
+  // Each side of the cluster, as the pairs of tiles facing each other across
+  // it.
+  let mut sides: Vec<Vec<(TilePoint, TilePoint)>> = vec![];
+  if top > 0 {
+    sides.push(
+      (left..right)
+        .map(|x| (TilePoint::new(x, top), TilePoint::new(x, top - 1)))
+        .collect(),
+    );
+  }
+  if bottom < map.height {
+    sides.push(
+      (left..right)
+        .map(|x| (TilePoint::new(x, bottom - 1), TilePoint::new(x, bottom)))
+        .collect(),
+    );
+  }
+  if left > 0 {
+    sides.push(
+      (top..bottom)
+        .map(|y| (TilePoint::new(left, y), TilePoint::new(left - 1, y)))
+        .collect(),
+    );
+  }
+  if right < map.width {
+    sides.push(
+      (top..bottom)
+        .map(|y| (TilePoint::new(right - 1, y), TilePoint::new(right, y)))
+        .collect(),
+    );
+  }
+
+  let mut out = vec![];
+  for side in sides {
+    // Split the side into runs of tiles that are open on both sides. A run
+    // also ends where the side climbs or drops a level, since units couldn't
+    // walk along it from one end to the other.
+    let open = |(a, b): &(TilePoint, TilePoint)| {
+      map.has_clearance(*a, clearance) && map.has_clearance(*b, clearance) && map.can_cross(*a, *b)
+    };
+    let mut runs = vec![];
+    let mut run = vec![];
+    for pair in side {
+      let continues = run.last().is_none_or(|(a, b): &(TilePoint, TilePoint)| {
+        map.can_cross(*a, pair.0) && map.can_cross(*b, pair.1)
+      });
+      if !continues {
+        runs.push(std::mem::take(&mut run));
+      }
+      if open(&pair) {
+        run.push(pair);
+      } else if !run.is_empty() {
+        runs.push(std::mem::take(&mut run));
+      }
+    }
+    if !run.is_empty() {
+      runs.push(run);
+    }
+    for run in runs {
+      if run.len() as u32 > MAX_ENTRANCE_WIDTH {
+        out.push(run[0]);
+        out.push(run[run.len() - 1]);
+      } else {
+        out.push(run[run.len() / 2]);
+      }
+    }
+  }
+  out
+}
+
+// Searches outwards from start without leaving bounds. For each tile reached,
+// returns the tile it was reached from and the cost of getting there. Only
+// tiles with enough clearance are searched, but start itself doesn't need it.
+fn search(
+  map: &Map,
+  bounds: TileRect,
+  start: TilePoint,
+  clearance: u8,
+) -> HashMap<TilePoint, (TilePoint, u32)> {
+  let mut reached: HashMap<TilePoint, (TilePoint, u32)> = HashMap::new();
+  reached.insert(start, (start, 0));
+  let mut to_visit = BinaryHeap::new();
+  to_visit.push(Reverse((0, start)));
+  while let Some(Reverse((cost, here))) = to_visit.pop() {
+    if cost > reached[&here].1 {
+      continue;
+    }
+    for p in here.neighbors4(map) {
+      if !bounds.contains(p) || !map.has_clearance(p, clearance) || !map.can_cross(here, p) {
+        continue;
+      }
+      let next_cost = cost + map.step_cost(here, p);
+      if reached.get(&p).is_none_or(|(_, best)| next_cost < *best) {
+        reached.insert(p, (here, next_cost));
+        to_visit.push(Reverse((next_cost, p)));
+      }
+    }
+  }
+  reached
+}
+
+// The path from the start of a search to end, which must have been reached.
+fn trace(reached: &HashMap<TilePoint, (TilePoint, u32)>, end: TilePoint) -> Vec<TilePoint> {
+  let mut path = vec![end];
+  let mut here = end;
+  while reached[&here].0 != here {
+    here = reached[&here].0;
+    path.push(here);
+  }
+  path.reverse();
+  path
+}
@@ -1,53 +1,105 @@
 #[allow(dead_code)]
 mod ability;
 #[allow(dead_code)]
+mod bench;
+#[allow(dead_code)]
+mod checksum;
+#[allow(dead_code)]
 mod dimensions;
 #[allow(dead_code)]
+mod editor;
+#[allow(dead_code)]
+mod entity;
+#[allow(dead_code)]
+mod flow_field;
+#[allow(dead_code)]
 mod game;
 #[allow(dead_code)]
+mod hpa;
+#[allow(dead_code)]
 mod map;
 #[allow(dead_code)]
+mod map_file;
+#[allow(dead_code)]
+mod mapgen;
+#[allow(dead_code)]
+mod pathfinding;
+#[allow(dead_code)]
+mod spatial;
+#[allow(dead_code)]
 mod sprite_sheet;
+#[allow(dead_code)]
+mod tiled;
+#[allow(dead_code)]
+mod vision;
 
 extern crate rand;
 extern crate sdl2;
 
 use sdl2::event::{Event, WindowEvent};
-use sdl2::image;
+use sdl2::image::{self, SaveSurface};
 use sdl2::keyboard::Keycode;
 use sdl2::mouse::MouseButton;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
-use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::render::{Canvas, RenderTarget, Texture, TextureCreator};
+use sdl2::surface::Surface;
 use sdl2::ttf::Font;
 use sdl2::video::{Window, WindowContext};
 use sdl2::Sdl;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::process::exit;
 use std::rc::Rc;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
-use crate::ability::{Ability, AbilityCommon, PointTargetedAbility};
-use crate::dimensions::{DisplayPoint, ToWorld, WindowPoint, WorldCoord, WorldPoint};
-use crate::map::{GridTile, TILE_WIDTH};
+use crate::ability::{
+  cast_non_targeted_group, cast_point_targeted_group, Ability, AbilityCommon, PointTargetedAbility,
+};
+use crate::checksum::StateChecker;
+use crate::dimensions::{DisplayPoint, ToWorld, WindowPoint, WorldCoord, WorldPoint, WorldRect};
+use crate::editor::{Brush, Editor, MAX_ELEVATION};
+use crate::entity::UID;
+use crate::game::{Cooldowns, Order, PlayerId};
+use crate::map::{GridTile, Map, TilePoint, ToTilePoint, TILE_WIDTH};
+use crate::map_file::MapEntity;
 use crate::sprite_sheet::SpriteSheet;
+use crate::vision::{Visibility, Vision};
 
 const EMPTY_TILE_COLOR: Color = Color::RGB(40, 42, 54);
 const OBSTACLE_COLOR: Color = Color::RGB(255, 184, 108);
+const ROAD_COLOR: Color = Color::RGB(98, 114, 164);
+const ROUGH_COLOR: Color = Color::RGB(68, 71, 90);
+const SHALLOW_WATER_COLOR: Color = Color::RGB(80, 140, 200);
+const DEEP_WATER_COLOR: Color = Color::RGB(30, 60, 140);
+const CLIFF_COLOR: Color = Color::RGB(120, 90, 60);
+const RAMP_COLOR: Color = Color::RGB(110, 100, 80);
+// How much lighter each elevation level is drawn than the one below it.
+const ELEVATION_SHADE: u8 = 14;
+// Tiles that have been explored but can't be seen right now are drawn with
+// their colors divided by this.
+const FOG_DIMMING: u8 = 3;
 const UNIT_COLOR: Color = Color::RGB(255, 121, 198);
 const UNIT_SELECTED_COLOR: Color = Color::RGB(80, 250, 123);
 const UNIT_MOVING_COLOR: Color = Color::RGB(189, 147, 249);
 const DRAG_PERIMETER_COLOR: Color = Color::RGB(0, 255, 0);
 const WAYPOINT_COLOR: Color = UNIT_MOVING_COLOR;
 const BUILDING_COLOR: Color = Color::RGB(139, 233, 253);
+const ENEMY_COLOR: Color = Color::RGB(255, 85, 85);
+const RESOURCE_COLOR: Color = Color::RGB(241, 250, 140);
+const HP_BAR_COLOR: Color = UNIT_SELECTED_COLOR;
+const HP_BAR_BACKGROUND_COLOR: Color = ENEMY_COLOR;
 
 const COLOR_WHITE: Color = Color::RGB(248, 248, 242);
+const START_LOCATION_COLOR: Color = UNIT_SELECTED_COLOR;
+const EDITOR_CURSOR_COLOR: Color = COLOR_WHITE;
 
 const WAYPOINT_RAD: u32 = 2;
 
 const SPRITE_SHEET_PATH: &str = "media/sprite-sheet.sps";
+// The map to play when none is given with --map.
+const DEFAULT_MAP_PATH: &str = "media/test-map.txt";
 const SHOW_UNIT_DEBUG_BOXES: bool = false;
 
 // TODO: Do some stuff to pick the right display / let user pick.
@@ -72,6 +124,13 @@ const LONG_FRAME_DEBUG: bool = false;
 
 const BUILDING_SELECTION_OFFSET: u32 = 3;
 const TRAIN_QUEUE_WIDTH: u32 = 8;
+const HP_BAR_HEIGHT: u32 = 4;
+const RESOURCE_NODE_INSET: u32 = 12;
+// How many pixels across each tile is in pictures saved with F12.
+const SCREENSHOT_TILE_SIZE: u32 = 16;
+// How many pixels across the longer side of a map exported to a PNG is at most,
+// unless it's given a tile size.
+const THUMBNAIL_SIZE: u32 = 1024;
 
 struct State<'canvas, 'b> {
   // "Immutable" stuff.
@@ -83,6 +142,12 @@ struct State<'canvas, 'b> {
   // State of the game.
   running: bool,
   game: game::State,
+  // The player whose units we can control.
+  player: PlayerId,
+  // The map being edited, if we're in the editor.
+  editor: Option<Editor>,
+  // Whether we're editing the map, rather than trying it out in a game.
+  editing: bool,
 
   // Interaction state.
   cursor_state: CursorState,
@@ -98,6 +163,8 @@ impl<'canvas, 'b> State<'canvas, 'b> {
     display_bounds: DisplayBounds,
     font: Font<'f, 'static>,
     text_renderer: CachingTextRenderer<'s>,
+    game: game::State,
+    editor: Option<Editor>,
   ) -> State<'s, 'f> {
     State {
       sprite_sheet,
@@ -106,11 +173,14 @@ impl<'canvas, 'b> State<'canvas, 'b> {
       text_renderer,
 
       running: true,
-      game: game::State::level1(),
+      game,
+      player: 0,
+      editing: editor.is_some(),
+      editor,
 
       cursor_state: CursorState::None,
       key_state: KeyState::new(),
-      camera_pos: WorldPoint::new(WorldCoord(0.), WorldCoord(0.)),
+      camera_pos: WorldPoint::new(WorldCoord::ZERO, WorldCoord::ZERO),
       // This is wrong, but will be set on the first WindowMove event.
       window_pos: DisplayPoint::new(0, 0),
       // This is wrong, but will be set on the next MouseMotion event.
@@ -131,7 +201,8 @@ enum CursorState {
   BoxSelect(BoxSelect),
   CameraDrag,
   // TODO: Handle case where it can be unit targeted.
-  AbilitySelected(Rc<dyn PointTargetedAbility>),
+  // Holds the ability of every selected caster that has it. Never empty.
+  AbilitySelected(Vec<Rc<dyn PointTargetedAbility>>),
 }
 
 #[derive(Clone, Copy)]
@@ -185,9 +256,15 @@ impl BoxSelect {
   fn resolve(&self, final_pt: WindowPoint, state: &mut State) {
     let camera_pos = state.camera_pos();
     let selection_rect = rect_from_points(self.from.to_window(camera_pos), final_pt);
+    let world_rect = WorldRect::from_points(self.from, final_pt.to_world(camera_pos));
+    let in_box: HashSet<(UID, PlayerId)> = state
+      .game
+      .unit_index
+      .in_rect(&world_rect)
+      .into_iter()
+      .collect();
     for unit in state.game.units.iter_mut() {
-      let unit_bounds = rect_from_center_rad(unit.pos.to_window(camera_pos), unit.window_rad());
-      unit.selected = selection_rect.has_intersection(unit_bounds);
+      unit.selected = unit.owner == state.player && in_box.contains(&(unit.uid, unit.owner));
     }
     for building in state.game.buildings.iter_mut() {
       let top_left = building.top_left_pos.to_world_point().to_window(camera_pos);
@@ -197,12 +274,67 @@ impl BoxSelect {
         building.width() * TILE_WIDTH,
         building.height() * TILE_WIDTH,
       );
-      building.selected = selection_rect.has_intersection(bounds);
+      building.selected = building.owner == state.player && selection_rect.has_intersection(bounds);
     }
   }
 }
 
 fn main() {
+  if std::env::args().nth(1).as_deref() == Some("--bench-pathfinding") {
+    bench::pathfinding();
+    return;
+  }
+  if std::env::args().nth(1).as_deref() == Some("--bench-spatial") {
+    bench::spatial();
+    return;
+  }
+  if let Some(path) = arg_value("--generate-map") {
+    generate_map(&path);
+    return;
+  }
+  if std::env::args().nth(1).as_deref() == Some("--import-tiled") {
+    import_tiled_map(std::env::args().nth(2), std::env::args().nth(3));
+    return;
+  }
+  if std::env::args().nth(1).as_deref() == Some("--export-png") {
+    export_map_png(std::env::args().nth(2), std::env::args().nth(3));
+    return;
+  }
+  if std::env::args().nth(1).as_deref() == Some("--check-map") {
+    check_maps(std::env::args().skip(2));
+    return;
+  }
+
+  // With --edit, the map is opened in the editor instead of played.
+  let loaded = match arg_value("--edit") {
+    Some(path) => Editor::open(&path).and_then(|editor| {
+      let game = game::State::from_map(&editor.path, editor.file.clone())?;
+      Ok((game, Some(editor)))
+    }),
+    None => {
+      let map_path = arg_value("--map").unwrap_or(DEFAULT_MAP_PATH.to_string());
+      game::State::from_map_file(&map_path).map(|game| (game, None))
+    }
+  };
+  let (game, editor) = loaded.unwrap_or_else(|e| {
+    println!("error loading map: {}", e);
+    exit(1);
+  });
+
+  // --checksums prints a checksum of the game's state after every tick.
+  // --record-state saves the whole state after every tick to a file, and
+  // --compare-state checks each tick against a file saved by an earlier run,
+  // printing the first thing that's different once they stop matching.
+  let checker = StateChecker::new(
+    std::env::args().any(|arg| arg == "--checksums"),
+    arg_value("--record-state").as_deref(),
+    arg_value("--compare-state").as_deref(),
+  )
+  .unwrap_or_else(|e| {
+    println!("couldn't open state file: {}", e);
+    exit(1);
+  });
+
   let sdl_context = sdl2::init().unwrap();
   let _sdl_image_context = image::init(image::InitFlag::PNG).unwrap();
 
@@ -239,12 +371,181 @@ fn main() {
       width: (DISPLAY_BR_X - DISPLAY_TL_X) as u32,
       height: (DISPLAY_BR_Y - DISPLAY_TL_Y) as u32,
     };
-    State::new(sprite_sheet, display_bounds, font, text_renderer)
+    State::new(
+      sprite_sheet,
+      display_bounds,
+      font,
+      text_renderer,
+      game,
+      editor,
+    )
   };
-  main_loop(state, canvas, sdl_context);
+  main_loop(state, canvas, sdl_context, checker);
 }
 
-fn main_loop(mut state: State, mut canvas: Canvas<Window>, sdl_context: Sdl) {
+// The argument after flag on the command line, if the flag was given.
+fn arg_value(flag: &str) -> Option<String> {
+  std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+// Makes a new map and saves it to path. The map is made from the --seed,
+// --players and --size (as WIDTHxHEIGHT) given on the command line. Without a
+// seed, a random one is used.
+fn generate_map(path: &str) {
+  let parse = |flag: &str| {
+    arg_value(flag).map(|value| {
+      value.parse().unwrap_or_else(|_| {
+        println!("couldn't parse {} {:?}", flag, value);
+        exit(1);
+      })
+    })
+  };
+  let seed = parse("--seed").unwrap_or_else(rand::random);
+  let players = parse("--players").unwrap_or(2);
+  let mut settings = mapgen::MapGenSettings::new(seed, players.try_into().unwrap_or(u32::MAX));
+  if let Some(size) = arg_value("--size") {
+    let (width, height) = size
+      .split_once('x')
+      .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+      .unwrap_or_else(|| {
+        println!("couldn't parse --size {:?}, expected WIDTHxHEIGHT", size);
+        exit(1);
+      });
+    settings.width = width;
+    settings.height = height;
+  }
+
+  let map_file = mapgen::generate(&settings).unwrap_or_else(|e| {
+    println!("{}", e);
+    exit(1);
+  });
+  if let Err(e) = std::fs::write(path, map_file.to_text()) {
+    println!("couldn't write {}: {}", path, e);
+    exit(1);
+  }
+  println!("wrote {} (seed {})", path, seed);
+}
+
+// Converts a map made in Tiled to our own format. Tiles get their terrain from
+// the --terrain-property given on the command line, or the default one.
+fn import_tiled_map(from: Option<String>, to: Option<String>) {
+  let (from, to) = match (from, to) {
+    (Some(from), Some(to)) => (from, to),
+    _ => {
+      println!("usage: --import-tiled TILED_MAP MAP [--terrain-property NAME]");
+      exit(1);
+    }
+  };
+  let terrain_property =
+    arg_value("--terrain-property").unwrap_or(tiled::DEFAULT_TERRAIN_PROPERTY.to_string());
+  let map_file = tiled::import(&from, &terrain_property).unwrap_or_else(|e| {
+    println!("{}", e);
+    exit(1);
+  });
+  if let Err(e) = std::fs::write(&to, map_file.to_text()) {
+    println!("couldn't write {}: {}", to, e);
+    exit(1);
+  }
+  println!("wrote {}", to);
+}
+
+// Draws a map to a PNG, along with everything on it when a game starts there
+// unless --terrain-only is given. Each tile is --tile-size pixels across.
+// Without it, tiles are as big as they can be without the picture being more
+// than THUMBNAIL_SIZE across, but no bigger than in the game and at least a
+// pixel.
+fn export_map_png(map_path: Option<String>, png_path: Option<String>) {
+  let (map_path, png_path) = match (map_path, png_path) {
+    (Some(map_path), Some(png_path)) => (map_path, png_path),
+    _ => {
+      println!("usage: --export-png MAP PNG [--tile-size PIXELS] [--terrain-only]");
+      exit(1);
+    }
+  };
+  let mut game = game::State::from_map_file(&map_path).unwrap_or_else(|e| {
+    println!("{}", e);
+    exit(1);
+  });
+  let longer_side = game.map.width.max(game.map.height).max(1);
+  let tile_size = arg_value("--tile-size").map_or(
+    (THUMBNAIL_SIZE / longer_side).clamp(1, TILE_WIDTH),
+    |size| {
+      size.parse().unwrap_or_else(|_| {
+        println!("couldn't parse --tile-size {:?}", size);
+        exit(1);
+      })
+    },
+  );
+  if std::env::args().any(|arg| arg == "--terrain-only") {
+    game.units.clear();
+    game.buildings.clear();
+    game.resource_nodes.clear();
+  }
+  if let Err(e) = export_png(&game, 0, &png_path, tile_size) {
+    println!("couldn't export {}: {}", png_path, e);
+    exit(1);
+  }
+  println!("wrote {}", png_path);
+}
+
+// Draws the whole of a game's map and everything on it, as seen by player, to a
+// PNG at path. It's drawn the same way as in a window, but offscreen, so it
+// works without one.
+fn export_png(
+  game: &game::State,
+  player: PlayerId,
+  path: &str,
+  tile_size: u32,
+) -> Result<(), String> {
+  let (width, height) = game
+    .map
+    .width
+    .checked_mul(tile_size)
+    .zip(game.map.height.checked_mul(tile_size))
+    .ok_or(format!(
+      "a {}x{} map is too big to draw at {} pixels a tile",
+      game.map.width, game.map.height, tile_size
+    ))?;
+  let surface = Surface::new(width, height, PixelFormatEnum::RGB24)?;
+  let mut canvas = surface.into_canvas()?;
+  let scale = tile_size as f32 / TILE_WIDTH as f32;
+  canvas.set_scale(scale, scale)?;
+  let texture_creator = canvas.texture_creator();
+  let sprite_sheet = SpriteSheet::from_file(SPRITE_SHEET_PATH, &texture_creator)?;
+
+  let camera_pos = WorldPoint::new(WorldCoord::ZERO, WorldCoord::ZERO);
+  canvas.set_draw_color(Color::BLACK);
+  canvas.clear();
+  draw_terrain(&mut canvas, &game.map, None, camera_pos);
+  draw_game(&mut canvas, game, &sprite_sheet, player, None, camera_pos);
+  canvas.present();
+  canvas.into_surface().save(path)
+}
+
+// Loads each of the map files the way a game would, without starting one, and
+// reports any problems with them. Exits with an error if there were any.
+fn check_maps(paths: impl Iterator<Item = String>) {
+  let mut ok = true;
+  for path in paths {
+    match game::State::from_map_file(&path) {
+      Ok(_) => println!("{}: ok", path),
+      Err(e) => {
+        println!("{}", e);
+        ok = false;
+      }
+    }
+  }
+  if !ok {
+    exit(1);
+  }
+}
+
+fn main_loop(
+  mut state: State,
+  mut canvas: Canvas<Window>,
+  sdl_context: Sdl,
+  mut checker: StateChecker,
+) {
   let mut event_pump = sdl_context.event_pump().unwrap();
   let mut mean_frame_dur = Duration::from_nanos(0);
   while state.running {
@@ -256,9 +557,12 @@ fn main_loop(mut state: State, mut canvas: Canvas<Window>, sdl_context: Sdl) {
     }
     let events_done = Instant::now();
 
-    // Update world.
+    // Update world. Nothing moves while the map's being edited.
     // TODO: Make game ticks operate on a different clock than render ticks.
-    state.game.tick();
+    if !state.editing {
+      state.game.tick();
+      checker.check(&state.game);
+    }
     let tick_done = Instant::now();
 
     // Render.
@@ -303,6 +607,9 @@ fn main_loop(mut state: State, mut canvas: Canvas<Window>, sdl_context: Sdl) {
 }
 
 fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
+  if state.editing && handle_editor_event(state, &event) {
+    return;
+  }
   match event {
     // Quit.
     Event::Quit { .. }
@@ -313,6 +620,31 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
       state.running = false;
     }
 
+    // F5: switch between editing the map and trying it out.
+    Event::KeyDown {
+      keycode: Some(Keycode::F5),
+      repeat: false,
+      ..
+    } if state.editor.is_some() => {
+      toggle_playtest(state);
+    }
+
+    // F12: save a picture of the whole map as the game stands.
+    Event::KeyDown {
+      keycode: Some(Keycode::F12),
+      repeat: false,
+      ..
+    } => {
+      let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+      let path = format!("screenshot-{}.png", secs);
+      match export_png(&state.game, state.player, &path, SCREENSHOT_TILE_SIZE) {
+        Ok(()) => println!("saved {}", path),
+        Err(e) => println!("couldn't save {}: {}", path, e),
+      }
+    }
+
     // Left mouse down / up: box select.
     Event::MouseButtonDown {
       x,
@@ -323,8 +655,12 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
       let scr_click = WindowPoint::new(x, y);
       let from = scr_click.to_world(state.camera_pos());
       match &state.cursor_state {
-        CursorState::AbilitySelected(ability) => {
-          ability.cast(&mut state.game, state.mouse_pos.to_world(state.camera_pos));
+        CursorState::AbilitySelected(abilities) => {
+          let target = state.mouse_pos.to_world(state.camera_pos);
+          let queue = state.key_state.shift();
+          if let Err(e) = cast_point_targeted_group(&mut state.game, abilities, target, queue) {
+            println!("couldn't cast {}: {}", abilities[0].name(), e);
+          }
           state.cursor_state = CursorState::None;
         }
         _ => {
@@ -345,7 +681,8 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
       state.cursor_state = CursorState::None;
     }
 
-    // Right mouse button -- issue or queue move command.
+    // Right mouse button -- issue or queue a move, or whatever else makes sense
+    // for what's under the cursor.
     Event::MouseButtonDown {
       x,
       y,
@@ -353,15 +690,25 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
       ..
     } => {
       let click_pos = WindowPoint::new(x, y).to_world(state.camera_pos());
+      let order = state.game.smart_order(state.player, click_pos);
+      let queue = state.key_state.shift();
+      if let Order::Move(dest) = order {
+        let selected: Vec<UID> = state
+          .game
+          .units
+          .iter()
+          .filter(|u| u.selected)
+          .map(|u| u.uid)
+          .collect();
+        state.game.order_group_move(&selected, dest, queue);
+        return;
+      }
       for unit in state.game.units.iter_mut() {
         if unit.selected {
-          if !state.key_state.shift() {
-            unit.waypoints.clear();
-          }
-          let found_path = unit.pathfind(&state.game.map, click_pos);
-          if !found_path {
-            unit.waypoints.push_back(click_pos);
+          if !queue {
+            unit.clear_orders();
           }
+          unit.queue_order(order.clone());
         }
       }
     }
@@ -399,8 +746,8 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
         }
         CursorState::CameraDrag => {
           state.camera_pos -= WorldPoint {
-            x: WorldCoord(xrel as f32),
-            y: WorldCoord(yrel as f32),
+            x: WorldCoord::from_int(xrel as i64),
+            y: WorldCoord::from_int(yrel as i64),
           };
         }
         CursorState::AbilitySelected(_) => {}
@@ -435,31 +782,53 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
         }
 
         Some(keycode) => {
-          // If the key corresponds to a usable ability on a selected
-          // unit, go into the AbilitySelected state, so that it will
-          // cast on the next click.
-          let ability = state
+          // Gather the matching ability from every selected unit and
+          // building, so that the whole selection can cast it.
+          let abilities: Vec<Ability> = state
             .game
             .units
             .iter()
             .filter(|u| u.selected)
-            .find_map(|u| u.abilities.iter().find(|ab| ab.keycode() == keycode))
-            .or_else(|| {
+            .flat_map(|u| u.abilities.iter())
+            .chain(
               state
                 .game
                 .buildings
                 .iter()
                 .filter(|b| b.selected)
-                .find_map(|b| b.abilities.iter().find(|ab| ab.keycode() == keycode))
-            })
-            .map(|ab| (*ab).clone());
-          if let Some(ability) = ability {
-            match ability {
-              Ability::NonTargeted(ability) => ability.cast(&mut state.game),
-              Ability::PointTargeted(ability) => {
-                state.cursor_state = CursorState::AbilitySelected(ability);
+                .flat_map(|b| b.abilities.iter()),
+            )
+            .filter(|ab| ab.keycode() == keycode)
+            .cloned()
+            .collect();
+          // Non-targeted abilities cast immediately. For point-targeted ones
+          // go into the AbilitySelected state, so that they cast on the next
+          // click.
+          match abilities.first() {
+            Some(Ability::NonTargeted(first)) => {
+              let name = first.name();
+              let abilities: Vec<_> = abilities
+                .into_iter()
+                .filter_map(|ab| match ab {
+                  Ability::NonTargeted(ab) => Some(ab),
+                  _ => None,
+                })
+                .collect();
+              if let Err(e) = cast_non_targeted_group(&mut state.game, &abilities) {
+                println!("couldn't cast {}: {}", name, e);
               }
             }
+            Some(Ability::PointTargeted(_)) => {
+              let abilities = abilities
+                .into_iter()
+                .filter_map(|ab| match ab {
+                  Ability::PointTargeted(ab) => Some(ab),
+                  _ => None,
+                })
+                .collect();
+              state.cursor_state = CursorState::AbilitySelected(abilities);
+            }
+            None => {}
           }
         }
 
@@ -505,44 +874,280 @@ fn handle_event(state: &mut State, _canvas: &mut Canvas<Window>, event: Event) {
   }
 }
 
+// Handles the events that do something different in the editor. Returns
+// whether the event has been dealt with. Anything else, like dragging the
+// camera around, works the same as in a game.
+//
+// Left click uses the brush, and right click removes start locations and
+// entities. The keys pick brushes:
+//   1-8       paint tiles, in the order of GridTile::ALL
+//   E         paint elevation; press again for the next level up
+//   P         place start locations
+//   U, B      place units and buildings; press again for the next type
+//   N         place resource nodes
+//   Tab       place things for the next player
+//   Ctrl+S    save
+//   Ctrl+arrows  make the map wider, narrower, taller or shorter
+fn handle_editor_event(state: &mut State, event: &Event) -> bool {
+  let camera_pos = state.camera_pos();
+  let tile_at = |x: i32, y: i32| {
+    let p = WindowPoint::new(x, y).to_world(camera_pos);
+    (p.x >= WorldCoord::ZERO && p.y >= WorldCoord::ZERO).then(|| p.to_tile_point())
+  };
+  let ctrl = state.key_state.ctrl();
+  let editor = state.editor.as_mut().unwrap();
+  match *event {
+    Event::MouseButtonDown {
+      x,
+      y,
+      mouse_btn: MouseButton::Left,
+      ..
+    } => {
+      if let Some(p) = tile_at(x, y) {
+        if let Err(e) = editor.paint(p) {
+          println!("{}", e);
+        }
+      }
+      editor.painting = editor.brush.paints_terrain();
+      true
+    }
+    Event::MouseButtonUp {
+      mouse_btn: MouseButton::Left,
+      ..
+    } => {
+      editor.painting = false;
+      true
+    }
+    Event::MouseButtonDown {
+      x,
+      y,
+      mouse_btn: MouseButton::Right,
+      ..
+    } => {
+      if let Some(p) = tile_at(x, y) {
+        editor.erase(p);
+      }
+      true
+    }
+    Event::MouseMotion { x, y, .. } => {
+      if let (true, Some(p)) = (editor.painting, tile_at(x, y)) {
+        let _ = editor.paint(p);
+      }
+      // The camera still needs to follow the mouse.
+      false
+    }
+
+    Event::KeyDown {
+      keycode: Some(keycode),
+      repeat: false,
+      ..
+    } => {
+      let (width, height) = (editor.file.map.width, editor.file.map.height);
+      let tile_keys = [
+        Keycode::Num1,
+        Keycode::Num2,
+        Keycode::Num3,
+        Keycode::Num4,
+        Keycode::Num5,
+        Keycode::Num6,
+        Keycode::Num7,
+        Keycode::Num8,
+      ];
+      match keycode {
+        Keycode::S if ctrl => match editor.save() {
+          Ok(()) => println!("saved {}", editor.path),
+          Err(e) => println!("{}", e),
+        },
+        Keycode::Left if ctrl => editor.resize(width - 1, height),
+        Keycode::Right if ctrl => editor.resize(width + 1, height),
+        Keycode::Up if ctrl => editor.resize(width, height - 1),
+        Keycode::Down if ctrl => editor.resize(width, height + 1),
+        _ if tile_keys.contains(&keycode) => {
+          let i = tile_keys.iter().position(|k| *k == keycode).unwrap();
+          editor.brush = Brush::Tile(GridTile::ALL[i]);
+        }
+        Keycode::E => {
+          editor.brush = match editor.brush {
+            Brush::Elevation(level) => Brush::Elevation((level + 1) % (MAX_ELEVATION + 1)),
+            _ => Brush::Elevation(1),
+          }
+        }
+        Keycode::P => editor.brush = Brush::StartLocation,
+        Keycode::U => {
+          editor.brush = match editor.brush {
+            Brush::Unit(i) => Brush::Unit((i + 1) % game::unit_types().len()),
+            _ => Brush::Unit(0),
+          }
+        }
+        Keycode::B => {
+          editor.brush = match editor.brush {
+            Brush::Building(i) => Brush::Building((i + 1) % game::building_types().len()),
+            _ => Brush::Building(0),
+          }
+        }
+        Keycode::N => editor.brush = Brush::ResourceNode,
+        Keycode::Tab => editor.next_owner(),
+        _ => return false,
+      }
+      true
+    }
+
+    _ => false,
+  }
+}
+
+// Starts a game on the map being edited, or goes back to editing it.
+fn toggle_playtest(state: &mut State) {
+  let editor = state.editor.as_ref().unwrap();
+  if !state.editing {
+    state.editing = true;
+  } else {
+    match game::State::from_map(&editor.path, editor.file.clone()) {
+      Ok(game) => {
+        state.game = game;
+        state.editing = false;
+      }
+      Err(e) => println!("couldn't start a game on the map: {}", e),
+    }
+  }
+  state.cursor_state = CursorState::None;
+}
+
 fn render(canvas: &mut Canvas<Window>, state: &mut State) {
   canvas.set_draw_color(Color::BLACK);
   canvas.clear();
+  if state.editing {
+    render_editor(canvas, state);
+    return;
+  }
 
-  // Draw terrain.
-  for tile in state.game.map.tiles() {
-    canvas.set_draw_color(match tile.tile {
-      GridTile::Empty => EMPTY_TILE_COLOR,
-      GridTile::Obstacle => OBSTACLE_COLOR,
-    });
-    let window_pos = tile.pos.to_world_point().to_window(state.camera_pos());
+  // Without a player to see through, there's no fog.
+  let player = state.game.players.get(state.player as usize);
+  let vision = player.map(|player| &player.vision);
+  draw_terrain(canvas, &state.game.map, vision, state.camera_pos());
+
+  // Draw where selected units are headed, underneath everything else.
+  let camera_pos = state.camera_pos();
+  for unit in state.game.units.iter().filter(|unit| unit.selected) {
+    for p in unit.waypoints.iter() {
+      draw_waypoint(canvas, p.to_window(camera_pos));
+    }
+    for order in unit.orders.iter() {
+      if let Some(target) = order.target(&state.game) {
+        draw_waypoint(canvas, target.to_window(camera_pos));
+      }
+      if let Order::CastAbility { ability, target } = order {
+        ability.draw(canvas, *target, camera_pos);
+      }
+    }
+  }
+  draw_game(
+    canvas,
+    &state.game,
+    &state.sprite_sheet,
+    state.player,
+    vision,
+    camera_pos,
+  );
+
+  // Draw box-selection box.
+  if let CursorState::BoxSelect(box_select) = &state.cursor_state {
+    canvas.set_draw_color(DRAG_PERIMETER_COLOR);
+    let _ = canvas.draw_rect(rect_from_points(
+      box_select.from.to_window(state.camera_pos()),
+      box_select.to.to_window(state.camera_pos()),
+    ));
+  }
+
+  // Draw abilities, if there are any selected units.
+  // TODO: Have a sense of which selected unit is "active", and show abilities from it.
+  let unit = state.game.units.iter().find(|unit| unit.selected);
+  let building = state.game.buildings.iter().find(|b| b.selected);
+  let abilities = if let Some(unit) = unit {
+    Some((&unit.abilities, &unit.cooldowns))
+  } else {
+    building.map(|building| (&building.abilities, &building.cooldowns))
+  };
+  if let Some((abilities, cooldowns)) = abilities {
+    for (i, ability) in abilities.iter().enumerate() {
+      let text = ability_text(ability, cooldowns);
+      let top_left = WindowPoint::new(0, i as i32 * state.font.height());
+      state
+        .text_renderer
+        .draw_to_canvas(canvas, &state.font, &text, top_left)
+        .expect("couldn't draw abilities");
+    }
+  }
+
+  // Draw the player's resources.
+  let resources = player.map_or(0, |player| player.resources);
+  let text = format!("Resources: {}", resources);
+  let top_left = WindowPoint::new(WINDOW_WIDTH as i32 / 2, 0);
+  state
+    .text_renderer
+    .draw_to_canvas(canvas, &state.font, &text, top_left)
+    .expect("couldn't draw resources");
+
+  if let CursorState::AbilitySelected(abilities) = &state.cursor_state {
+    let ability = &abilities[0];
+    let top_left = WindowPoint::new(0, WINDOW_HEIGHT as i32 - state.font.height());
+    state
+      .text_renderer
+      .draw_to_canvas(canvas, &state.font, ability.name(), top_left)
+      .expect("couldn't draw active ability");
+
+    let camera_pos = state.camera_pos();
+    ability.draw(canvas, state.mouse_pos.to_world(camera_pos), camera_pos);
+  }
+}
+
+// Draws the resource nodes, units and buildings in a game, as seen by player.
+// With their vision, only resource nodes they've explored and enemies they can
+// see are drawn.
+fn draw_game<T: RenderTarget>(
+  canvas: &mut Canvas<T>,
+  game: &game::State,
+  sprite_sheet: &SpriteSheet,
+  player: PlayerId,
+  vision: Option<&Vision>,
+  camera_pos: WorldPoint,
+) {
+  let visibility = |p: TilePoint| vision.map_or(Visibility::Visible, |v| v.get(p));
+  let hidden =
+    |owner: PlayerId, p: TilePoint| owner != player && visibility(p) != Visibility::Visible;
+
+  // Draw resource nodes.
+  canvas.set_draw_color(RESOURCE_COLOR);
+  for node in game.resource_nodes.iter() {
+    if visibility(node.pos) == Visibility::Unexplored {
+      continue;
+    }
+    let top_left = node.pos.to_world_point().to_window(camera_pos);
     let _ = canvas.fill_rect(Rect::new(
-      window_pos.x(),
-      window_pos.y(),
-      TILE_WIDTH,
-      TILE_WIDTH,
+      top_left.x() + RESOURCE_NODE_INSET as i32,
+      top_left.y() + RESOURCE_NODE_INSET as i32,
+      TILE_WIDTH - 2 * RESOURCE_NODE_INSET,
+      TILE_WIDTH - 2 * RESOURCE_NODE_INSET,
     ));
   }
 
   // Draw units.
-  for unit in state.game.units.iter() {
-    if unit.selected {
-      for p in unit.waypoints.iter() {
-        draw_waypoint(canvas, p.to_window(state.camera_pos()));
-      }
+  for unit in game.units.iter() {
+    if hidden(unit.owner, unit.pos.to_tile_point()) {
+      continue;
     }
 
     // Draw unit.
-    let bounds = rect_from_center_rad(unit.pos.to_window(state.camera_pos()), unit.window_rad());
-    let _ =
-      state
-        .sprite_sheet
-        .blit_sprite_to_rect(unit.unit_type.sprite_key.as_str(), canvas, bounds);
+    let bounds = rect_from_center_rad(unit.pos.to_window(camera_pos), unit.window_rad());
+    let _ = sprite_sheet.blit_sprite_to_rect(unit.unit_type.sprite_key.as_str(), canvas, bounds);
 
-    // Draw debug box around the unit.
-    if SHOW_UNIT_DEBUG_BOXES || unit.selected {
+    // Draw debug box around the unit. Enemies always get one.
+    let is_enemy = unit.owner != player;
+    if SHOW_UNIT_DEBUG_BOXES || unit.selected || is_enemy {
       canvas.set_draw_color(if unit.selected {
         UNIT_SELECTED_COLOR
+      } else if is_enemy {
+        ENEMY_COLOR
       } else if unit.move_queued() {
         UNIT_MOVING_COLOR
       } else {
@@ -550,22 +1155,51 @@ fn render(canvas: &mut Canvas<Window>, state: &mut State) {
       });
       let _ = canvas.draw_rect(bounds);
     }
+
+    if unit.hp < unit.unit_type.max_hp {
+      draw_hp_bar(canvas, bounds, unit.hp, unit.unit_type.max_hp);
+    }
   }
 
-  for building in state.game.buildings.iter() {
-    // Draw building.
-    canvas.set_draw_color(BUILDING_COLOR);
-    let top_left = building
-      .top_left_pos
-      .to_world_point()
-      .to_window(state.camera_pos());
+  for building in game.buildings.iter() {
+    let mut tiles = (0..building.height()).flat_map(|dy| {
+      (0..building.width()).map(move |dx| building.top_left_pos + TilePoint::new(dx, dy))
+    });
+    if tiles.all(|p| hidden(building.owner, p)) {
+      continue;
+    }
+
+    // Draw building. Unfinished buildings fill up from the bottom as they're
+    // constructed.
+    canvas.set_draw_color(if building.owner == player {
+      BUILDING_COLOR
+    } else {
+      ENEMY_COLOR
+    });
+    let top_left = building.top_left_pos.to_world_point().to_window(camera_pos);
     let bounds = Rect::new(
       top_left.x,
       top_left.y,
       building.width() * TILE_WIDTH,
       building.height() * TILE_WIDTH,
     );
-    let _ = canvas.fill_rect(bounds);
+    if building.under_construction() {
+      let build_time = building.building_type.build_time.ticks;
+      let done = build_time - building.construction_left.ticks;
+      let filled_height = bounds.height() * done / build_time;
+      let _ = canvas.draw_rect(bounds);
+      let _ = canvas.fill_rect(Rect::new(
+        bounds.x,
+        bounds.bottom() - filled_height as i32,
+        bounds.width(),
+        filled_height,
+      ));
+    } else {
+      let _ = canvas.fill_rect(bounds);
+    }
+    if building.hp < building.building_type.max_hp {
+      draw_hp_bar(canvas, bounds, building.hp, building.building_type.max_hp);
+    }
 
     // Draw selection box around the building.
     if building.selected {
@@ -591,51 +1225,170 @@ fn render(canvas: &mut Canvas<Window>, state: &mut State) {
       ));
     }
   }
+}
 
-  // Draw box-selection box.
-  if let CursorState::BoxSelect(box_select) = &state.cursor_state {
-    canvas.set_draw_color(DRAG_PERIMETER_COLOR);
-    let _ = canvas.draw_rect(rect_from_points(
-      box_select.from.to_window(state.camera_pos()),
-      box_select.to.to_window(state.camera_pos()),
+// Draws the map's tiles. With a player's vision, the tiles they haven't explored
+// are left black, and those they can't see right now are dimmed.
+fn draw_terrain<T: RenderTarget>(
+  canvas: &mut Canvas<T>,
+  map: &Map,
+  vision: Option<&Vision>,
+  camera_pos: WorldPoint,
+) {
+  for tile in map.tiles() {
+    let visibility = vision.map_or(Visibility::Visible, |v| v.get(tile.pos));
+    if visibility == Visibility::Unexplored {
+      continue;
+    }
+    let color = match tile.tile {
+      GridTile::Empty => EMPTY_TILE_COLOR,
+      GridTile::Obstacle => OBSTACLE_COLOR,
+      GridTile::Road => ROAD_COLOR,
+      GridTile::Rough => ROUGH_COLOR,
+      GridTile::ShallowWater => SHALLOW_WATER_COLOR,
+      GridTile::DeepWater => DEEP_WATER_COLOR,
+      GridTile::Cliff => CLIFF_COLOR,
+      GridTile::Ramp => RAMP_COLOR,
+    };
+    let shade = ELEVATION_SHADE.saturating_mul(map.elevation(tile.pos));
+    let dimming = if visibility == Visibility::Explored {
+      FOG_DIMMING
+    } else {
+      1
+    };
+    canvas.set_draw_color(Color::RGB(
+      color.r.saturating_add(shade) / dimming,
+      color.g.saturating_add(shade) / dimming,
+      color.b.saturating_add(shade) / dimming,
+    ));
+    let window_pos = tile.pos.to_world_point().to_window(camera_pos);
+    let _ = canvas.fill_rect(Rect::new(
+      window_pos.x(),
+      window_pos.y(),
+      TILE_WIDTH,
+      TILE_WIDTH,
     ));
   }
+}
 
-  // Draw abilities, if there are any selected units.
-  // TODO: Have a sense of which selected unit is "active", and show abilities from it.
-  let unit = state.game.units.iter().find(|unit| unit.selected);
-  let building = state.game.buildings.iter().find(|b| b.selected);
-  if let Some(unit) = unit {
-    for ability in unit.abilities.iter() {
-      // TODO: Handle multiple abilities. Same for buildings.
-      let text = format!("[{}] {}", ability.keycode(), ability.name());
-      let top_left = WindowPoint::new(0, 0);
-      state
-        .text_renderer
-        .draw_to_canvas(canvas, &state.font, &text, top_left)
-        .expect("couldn't draw units' abilities");
-    }
-  } else if let Some(building) = building {
-    for ability in building.abilities.iter() {
-      let text = format!("[{}] {}", ability.keycode(), ability.name());
-      let top_left = WindowPoint::new(0, 0);
-      state
-        .text_renderer
-        .draw_to_canvas(canvas, &state.font, &text, top_left)
-        .expect("couldn't draw units' abilities");
+// Draws the map being edited: its terrain, start locations and entities, the
+// tile under the cursor, and what the brush is.
+fn render_editor(canvas: &mut Canvas<Window>, state: &mut State) {
+  let camera_pos = state.camera_pos();
+  let editor = state.editor.as_ref().unwrap();
+  draw_terrain(canvas, &editor.file.map, None, camera_pos);
+  let tile_rect = |p: TilePoint, width: u32, height: u32| {
+    let top_left = p.to_world_point().to_window(camera_pos);
+    Rect::new(
+      top_left.x(),
+      top_left.y(),
+      width * TILE_WIDTH,
+      height * TILE_WIDTH,
+    )
+  };
+
+  for (_, entity) in editor.file.entities.iter() {
+    match entity {
+      MapEntity::Unit { unit_type, pos, .. } => {
+        let unit_type = game::unit_types().into_iter().find(|t| t.name == unit_type);
+        if let Some(unit_type) = unit_type {
+          let center = pos.center_to_world_point().to_window(camera_pos);
+          let _ = state.sprite_sheet.blit_sprite_to_rect(
+            unit_type.sprite_key.as_str(),
+            canvas,
+            rect_from_center_rad(center, unit_type.radius.to_f32() as u32),
+          );
+        }
+      }
+      MapEntity::Building {
+        building_type,
+        owner,
+        pos,
+      } => {
+        let building_type = game::building_types()
+          .into_iter()
+          .find(|t| t.name == building_type);
+        if let Some(building_type) = building_type {
+          canvas.set_draw_color(if *owner == state.player {
+            BUILDING_COLOR
+          } else {
+            ENEMY_COLOR
+          });
+          let _ = canvas.fill_rect(tile_rect(*pos, building_type.width, building_type.height));
+        }
+      }
+      MapEntity::ResourceNode { pos, .. } => {
+        let bounds = tile_rect(*pos, 1, 1);
+        canvas.set_draw_color(RESOURCE_COLOR);
+        let _ = canvas.fill_rect(Rect::new(
+          bounds.x() + RESOURCE_NODE_INSET as i32,
+          bounds.y() + RESOURCE_NODE_INSET as i32,
+          TILE_WIDTH - 2 * RESOURCE_NODE_INSET,
+          TILE_WIDTH - 2 * RESOURCE_NODE_INSET,
+        ));
+      }
     }
   }
 
-  if let CursorState::AbilitySelected(ability) = &state.cursor_state {
-    let top_left = WindowPoint::new(0, WINDOW_HEIGHT as i32 - state.font.height());
+  // Start locations are outlined, and labelled with their player.
+  for (player, p) in editor.file.start_locations.iter().enumerate() {
+    let bounds = tile_rect(*p, 1, 1);
+    canvas.set_draw_color(START_LOCATION_COLOR);
+    let _ = canvas.draw_rect(bounds);
     state
       .text_renderer
-      .draw_to_canvas(canvas, &state.font, ability.name(), top_left)
-      .expect("couldn't draw active ability");
+      .draw_to_canvas(canvas, &state.font, &player.to_string(), bounds.top_left())
+      .expect("couldn't draw start location");
+  }
 
-    let camera_pos = state.camera_pos();
-    ability.draw(canvas, state.mouse_pos.to_world(camera_pos), camera_pos);
+  let mouse_pos = state.mouse_pos.to_world(camera_pos);
+  if mouse_pos.x >= WorldCoord::ZERO && mouse_pos.y >= WorldCoord::ZERO {
+    canvas.set_draw_color(EDITOR_CURSOR_COLOR);
+    let _ = canvas.draw_rect(tile_rect(mouse_pos.to_tile_point(), 1, 1));
   }
+
+  let text = format!(
+    "{}{} ({}x{})  brush: {}  player: {}",
+    editor.path,
+    if editor.unsaved { "*" } else { "" },
+    editor.file.map.width,
+    editor.file.map.height,
+    editor.brush.name(),
+    editor.owner
+  );
+  let top_left = WindowPoint::new(0, WINDOW_HEIGHT as i32 - state.font.height());
+  state
+    .text_renderer
+    .draw_to_canvas(canvas, &state.font, &text, top_left)
+    .expect("couldn't draw editor status");
+}
+
+// The label for an ability in the ability list. Cooldowns are shown in whole
+// seconds so the text renderer doesn't cache a texture per tick.
+fn ability_text(ability: &Ability, cooldowns: &Cooldowns) -> String {
+  match cooldowns.remaining(ability.name()) {
+    Some(left) => format!(
+      "[{}] {} ({}s)",
+      ability.keycode(),
+      ability.name(),
+      left.secs_ceil()
+    ),
+    None => format!("[{}] {}", ability.keycode(), ability.name()),
+  }
+}
+
+// Draws a health bar just above the bounds of a unit or building.
+fn draw_hp_bar<T: RenderTarget>(canvas: &mut Canvas<T>, bounds: Rect, hp: u32, max_hp: u32) {
+  let y = bounds.y - HP_BAR_HEIGHT as i32 - 1;
+  canvas.set_draw_color(HP_BAR_BACKGROUND_COLOR);
+  let _ = canvas.fill_rect(Rect::new(bounds.x, y, bounds.width(), HP_BAR_HEIGHT));
+  canvas.set_draw_color(HP_BAR_COLOR);
+  let _ = canvas.fill_rect(Rect::new(
+    bounds.x,
+    y,
+    bounds.width() * hp / max_hp,
+    HP_BAR_HEIGHT,
+  ));
 }
 
 fn draw_waypoint(canvas: &mut Canvas<Window>, p: WindowPoint) {
@@ -1,16 +1,39 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
 use std::ops::Add;
 
 use crate::dimensions::{WorldCoord as Coord, WorldPoint as Point, WorldRect as Rect};
+use crate::map_file::{MapError, MapFile};
 
 pub const TILE_WIDTH: u32 = 64;
 pub const TILE_WIDTH_F32: f32 = 64.;
+pub const TILE_WIDTH_COORD: Coord = Coord::from_int(TILE_WIDTH as i64);
+// How far around an occupied or vacated rect to look for ways around it, before
+// giving up and working out the regions for the whole map again.
+const REGION_SEARCH_MARGIN: u32 = 16;
 
+#[derive(Clone)]
 pub struct Map {
   // Width and height are measured in grid units.
   pub width: u32,
   pub height: u32,
 
   pub grid_tiles: Vec<GridTile>,
+  // The elevation level of each tile. Units can only walk between levels on
+  // ramps, and can't see up to a higher level than the one they're on.
+  elevation: Vec<u8>,
+  // How many buildings are standing on each tile. Units can't walk through a
+  // tile with a building on it, even if the tile itself is empty.
+  occupancy: Vec<u8>,
+  // How far each tile is from the nearest unwalkable tile or the edge of the
+  // map, counting diagonal steps as one. Unwalkable tiles have clearance 0.
+  // Something centered on a tile with clearance c has c - 1 free tiles on
+  // every side of it.
+  clearance: Vec<u8>,
+  // Which connected area of walkable tiles each tile is in. Two tiles are in
+  // the same region exactly when a unit could walk from one to the other.
+  // Unwalkable tiles aren't in any region.
+  regions: Vec<Option<u32>>,
 }
 
 // A tile is square with side length L:
@@ -25,59 +48,34 @@ pub struct Map {
 // actually in the tile. The tile is left- and up- inclusive and right-
 // and down- exclusive.
 impl Map {
-  // Reads a Map from a file.
-  //
-  // The format is like so:
-  // WIDTH
-  // HEIGHT
-  // TILES
-  //
-  // TILES is a grid of WIDTH by HEIGHT tile entries. Each entry is either X for
-  // a wall or O for open.
-  pub fn from_file(path: &str) -> Result<Map, String> {
-    let file = std::fs::read_to_string(path).map_err(|e| format!("err reading file: {:?}", e))?;
-    let mut lines = file.lines();
-
-    let width: u32 = lines
-      .next()
-      .ok_or("map missing WIDTH")?
-      .parse()
-      .or(Err("failed to parse WIDTH"))?;
-    let height: u32 = lines
-      .next()
-      .ok_or("map missing HEIGHT")?
-      .parse()
-      .or(Err("failed to parse WIDTH"))?;
-    let mut grid_tiles = Vec::new();
-    grid_tiles.reserve(width as usize * height as usize);
-    for i in 0..height {
-      let row = lines.next().ok_or(format!(
-        "map ends at row {} of expected HEIGHT={}",
-        i, height
-      ))?;
-      if row.len() == width as usize {
-        for c in row.chars() {
-          match c {
-            'X' => grid_tiles.push(GridTile::Obstacle),
-            'O' => grid_tiles.push(GridTile::Empty),
-            _ => {}
-          }
-        }
-      } else {
-        return Err(format!(
-          "row {} has length {}, when it should be WIDTH={}",
-          i,
-          row.len(),
-          width
-        ));
-      }
-    }
+  // Makes a flat map.
+  pub fn new(width: u32, height: u32, grid_tiles: Vec<GridTile>) -> Map {
+    let elevation = vec![0; grid_tiles.len()];
+    Map::with_elevation(width, height, grid_tiles, elevation)
+  }
 
-    Ok(Map {
+  pub fn with_elevation(
+    width: u32,
+    height: u32,
+    grid_tiles: Vec<GridTile>,
+    elevation: Vec<u8>,
+  ) -> Map {
+    let mut map = Map {
       width,
       height,
+      elevation,
+      occupancy: vec![0; grid_tiles.len()],
+      clearance: vec![],
+      regions: vec![],
       grid_tiles,
-    })
+    };
+    map.occupancy_changed();
+    map
+  }
+
+  // Reads a Map from a file in either of the map formats; see MapFile.
+  pub fn from_file(path: &str) -> Result<Map, MapError> {
+    MapFile::from_file(path).map(|file| file.map)
   }
 
   pub fn get_tile(&self, p: TilePoint) -> Option<GridTile> {
@@ -92,6 +90,653 @@ impl Map {
     Some(self.grid_tiles[index])
   }
 
+  // Whether units can walk on the tile: it's passable terrain, and there's no
+  // building on it.
+  pub fn is_walkable(&self, p: TilePoint) -> bool {
+    self.get_tile(p).is_some_and(|t| t.is_walkable())
+      && self.occupancy[(p.x + p.y * self.width) as usize] == 0
+  }
+
+  // How much it costs pathfinding to step between two neighbouring tiles.
+  // Half the step is spent on each tile, so it's the same both ways.
+  pub fn step_cost(&self, a: TilePoint, b: TilePoint) -> u32 {
+    let cost = |p| {
+      self
+        .get_tile(p)
+        .map_or(GridTile::Empty.move_cost(), |t| t.move_cost())
+    };
+    cost(a) + cost(b)
+  }
+
+  // How fast units move at p, as a multiple of their usual speed.
+  pub fn speed_multiplier_at(&self, p: Point) -> Coord {
+    self
+      .get_tile_at(p)
+      .map_or(Coord::from_int(1), |t| t.speed_multiplier())
+  }
+
+  // The elevation level of the tile. Zero off the map.
+  pub fn elevation(&self, p: TilePoint) -> u8 {
+    if self.width <= p.x || self.height <= p.y {
+      return 0;
+    }
+    self.elevation[(p.x + p.y * self.width) as usize]
+  }
+
+  // Whether a unit can step straight from tile a to tile b, as far as
+  // elevation goes: they're on the same level, or one is a ramp to the other.
+  pub fn can_cross(&self, a: TilePoint, b: TilePoint) -> bool {
+    let (from, to) = (self.elevation(a), self.elevation(b));
+    let on_ramp =
+      self.get_tile(a) == Some(GridTile::Ramp) || self.get_tile(b) == Some(GridTile::Ramp);
+    from == to || (on_ramp && from.abs_diff(to) == 1)
+  }
+
+  // Whether something at from can see something at to. Nobody can see up to
+  // higher ground.
+  pub fn can_see_elevation(&self, from: Point, to: Point) -> bool {
+    let level = |p: Point| {
+      self
+        .tile_coords_at(p)
+        .map_or(0, |(x, y)| self.elevation(TilePoint { x, y }))
+    };
+    level(to) <= level(from)
+  }
+
+  // Whether something at a can see something at b: they're both on the map,
+  // b isn't on higher ground than a, and nothing on the way between them blocks
+  // sight. Every tile the line between them passes through counts, even ones
+  // it only clips the corner of, apart from the tiles at either end, so walls
+  // themselves can be seen.
+  pub fn line_of_sight(&self, a: Point, b: Point) -> bool {
+    if self.get_tile_at(a).is_none() || self.get_tile_at(b).is_none() {
+      return false;
+    }
+    let (from, to) = (a.to_tile_point(), b.to_tile_point());
+    let level = self.elevation(from);
+    self.can_see_elevation(a, b)
+      && self
+        .tiles_on_line(a, b)
+        .into_iter()
+        .all(|p| p == from || p == to || !self.blocks_sight(p, level))
+  }
+
+  // The tiles the line from a to b passes through, in order, leaving out any
+  // that are off the map. Where the line passes exactly through the corner of
+  // a tile, both of the tiles on either side of the corner are included too.
+  pub fn tiles_on_line(&self, a: Point, b: Point) -> Vec<TilePoint> {
+    let tile = |c: Coord| c.floor().div_euclid(TILE_WIDTH as i64);
+    let (mut x, mut y) = (tile(a.x), tile(a.y));
+    let (end_x, end_y) = (tile(b.x), tile(b.y));
+    let (step_x, step_y) = ((end_x - x).signum(), (end_y - y).signum());
+    // How far across and down from a the line next crosses a vertical and a
+    // horizontal edge between tiles. Whichever of those is a smaller part of
+    // the whole way across or down is the edge the line gets to first.
+    let first_edge = |start: Coord, tile: i64, step: i64| match step {
+      1 => Coord::from_int(tile + 1) * TILE_WIDTH_COORD - start,
+      _ => start - Coord::from_int(tile) * TILE_WIDTH_COORD,
+    };
+    let mut edge_x = first_edge(a.x, x, step_x);
+    let mut edge_y = first_edge(a.y, y, step_y);
+    let (across, down) = ((b.x - a.x).abs(), (b.y - a.y).abs());
+
+    let mut tiles = vec![];
+    let mut visit = |x: i64, y: i64| {
+      if 0 <= x && x < self.width as i64 && 0 <= y && y < self.height as i64 {
+        tiles.push(TilePoint::new(x as u32, y as u32));
+      }
+    };
+    visit(x, y);
+    while (x, y) != (end_x, end_y) {
+      let next = match (step_x, step_y) {
+        (0, _) => Ordering::Greater,
+        (_, 0) => Ordering::Less,
+        _ => (edge_x * down).cmp(&(edge_y * across)),
+      };
+      match next {
+        Ordering::Equal => {
+          // Through a corner.
+          visit(x + step_x, y);
+          visit(x, y + step_y);
+          x += step_x;
+          y += step_y;
+          edge_x += TILE_WIDTH_COORD;
+          edge_y += TILE_WIDTH_COORD;
+        }
+        Ordering::Less => {
+          x += step_x;
+          edge_x += TILE_WIDTH_COORD;
+        }
+        Ordering::Greater => {
+          y += step_y;
+          edge_y += TILE_WIDTH_COORD;
+        }
+      }
+      visit(x, y);
+      // Rounding the products can make the line miss the end by a tile; stop
+      // rather than walking off forever.
+      if (x - end_x) * step_x > 0 || (y - end_y) * step_y > 0 {
+        break;
+      }
+    }
+    tiles
+  }
+
+  // The tiles that can be seen from the center of origin, out to radius tiles
+  // away. The same things block sight as for line_of_sight, but this is a bit
+  // more forgiving of lines that only clip the corner of a wall, so it sees
+  // some tiles that line_of_sight between tile centers wouldn't.
+  //
+  // This works by shadowcasting: each quarter of the area around origin is
+  // scanned a row at a time, working outwards. Each row is scanned between two
+  // slopes, which start out as the diagonals either side of the quarter. When
+  // the scan passes something that blocks sight, the rest of the row beyond
+  // it is scanned separately, with the slopes narrowed to leave out the part
+  // that's in its shadow.
+  pub fn visible_tiles(&self, origin: TilePoint, radius: u32) -> Vec<TilePoint> {
+    let level = self.elevation(origin);
+    let radius = radius as i64;
+    let mut visible = vec![origin];
+    // Each quarter is given by which way rows go across, and which way they go
+    // out from origin.
+    for (across, out) in [
+      ((1, 0), (0, -1)),
+      ((1, 0), (0, 1)),
+      ((0, 1), (1, 0)),
+      ((0, 1), (-1, 0)),
+    ] {
+      let at = |depth: i64, col: i64| {
+        (
+          origin.x as i64 + col * across.0 + depth * out.0,
+          origin.y as i64 + col * across.1 + depth * out.1,
+        )
+      };
+      let on_map =
+        |(x, y): (i64, i64)| 0 <= x && x < self.width as i64 && 0 <= y && y < self.height as i64;
+      // The rows still to scan, with their distance out from origin and the
+      // slopes they're scanned between. Slopes are fractions, kept as a
+      // numerator and a positive denominator so that everything is exact.
+      let mut rows = vec![(1, (-1, 1), (1, 1))];
+      while let Some((depth, mut start, end)) = rows.pop() {
+        if depth > radius {
+          continue;
+        }
+        // The columns from round(depth * start) to round(depth * end), with
+        // halves rounded towards the middle of the row.
+        let (start_num, start_den): (i64, i64) = start;
+        let (end_num, end_den): (i64, i64) = end;
+        let first_col = (2 * depth * start_num + start_den).div_euclid(2 * start_den);
+        let last_col = -(end_den - 2 * depth * end_num).div_euclid(2 * end_den);
+        let mut was_blocked = None;
+        for col in first_col..=last_col {
+          let (x, y) = at(depth, col);
+          let p = TilePoint::new(x.max(0) as u32, y.max(0) as u32);
+          let blocked = !on_map((x, y)) || self.blocks_sight(p, level);
+          // Tiles whose centers are between the slopes can be seen, and so
+          // can walls the scan runs into.
+          let centered = depth * start.0 <= col * start.1 && col * end.1 <= depth * end.0;
+          let in_range = col * col + depth * depth <= radius * radius;
+          let seen = centered || self.get_tile(p).is_some_and(|t| t.is_opaque());
+          if on_map((x, y)) && in_range && seen && self.elevation(p) <= level {
+            visible.push(p);
+          }
+          let slope = (2 * col - 1, 2 * depth);
+          if was_blocked == Some(true) && !blocked {
+            start = slope;
+          }
+          if was_blocked == Some(false) && blocked {
+            rows.push((depth + 1, start, slope));
+          }
+          was_blocked = Some(blocked);
+        }
+        if was_blocked == Some(false) {
+          rows.push((depth + 1, start, end));
+        }
+      }
+    }
+    // Tiles on the diagonals are in two quarters.
+    visible.sort();
+    visible.dedup();
+    visible
+  }
+
+  // Whether the tile stops something on the given elevation level seeing past
+  // it.
+  fn blocks_sight(&self, p: TilePoint, level: u8) -> bool {
+    self.get_tile(p).is_some_and(|t| t.is_opaque()) || self.elevation(p) > level
+  }
+
+  // Whether a unit could walk from a to b. Both have to be walkable.
+  pub fn is_reachable(&self, a: TilePoint, b: TilePoint) -> bool {
+    let region = self.region(a);
+    region.is_some() && region == self.region(b)
+  }
+
+  // The region the tile is in; see Map::regions.
+  pub fn region(&self, p: TilePoint) -> Option<u32> {
+    if self.width <= p.x || self.height <= p.y {
+      return None;
+    }
+    self.regions[(p.x + p.y * self.width) as usize]
+  }
+
+  // The walkable tile closest to p, as the crow flies, if there are any.
+  pub fn nearest_walkable(&self, p: TilePoint) -> Option<TilePoint> {
+    self.nearest_tile(p, |q| self.is_walkable(q))
+  }
+
+  // The tile closest to p, as the crow flies, that matches the predicate.
+  pub fn nearest_tile(&self, p: TilePoint, pred: impl Fn(TilePoint) -> bool) -> Option<TilePoint> {
+    // Squared, which compares the same way.
+    let distance = |q: TilePoint| {
+      let (dx, dy) = (q.x.abs_diff(p.x) as u64, q.y.abs_diff(p.y) as u64);
+      dx * dx + dy * dy
+    };
+    // Look in ever bigger squares around p. A tile in the next square out can
+    // still be closer than the corner of this one, so keep going until the
+    // squares are further away than the best tile found.
+    let mut best: Option<(u64, TilePoint)> = None;
+    for r in 0..u32::max(self.width, self.height) {
+      if let Some((d, _)) = best {
+        if (r as u64) * (r as u64) > d {
+          break;
+        }
+      }
+      let (left, top) = (p.x as i64 - r as i64, p.y as i64 - r as i64);
+      let (right, bottom) = (p.x as i64 + r as i64, p.y as i64 + r as i64);
+      let ring = (left..=right)
+        .flat_map(|x| [(x, top), (x, bottom)])
+        .chain((top + 1..bottom).flat_map(|y| [(left, y), (right, y)]));
+      for (x, y) in ring {
+        if x < 0 || y < 0 {
+          continue;
+        }
+        let q = TilePoint::new(x as u32, y as u32);
+        if pred(q) && best.is_none_or(|(d, _)| distance(q) < d) {
+          best = Some((distance(q), q));
+        }
+      }
+    }
+    best.map(|(_, q)| q)
+  }
+
+  // How many buildings are on each tile, a row of tiles to a line.
+  pub fn describe_occupancy(&self) -> Vec<String> {
+    self
+      .occupancy
+      .chunks(self.width.max(1) as usize)
+      .map(|row| {
+        row
+          .iter()
+          .map(|n| n.to_string())
+          .collect::<Vec<_>>()
+          .join(" ")
+      })
+      .collect()
+  }
+
+  // Marks the tiles under a building as occupied.
+  pub fn occupy(&mut self, top_left: TilePoint, width: u32, height: u32) {
+    for p in self.tiles_in(top_left, width, height) {
+      self.occupancy[p] = self.occupancy[p].saturating_add(1);
+    }
+    self.occupancy_changed_in(top_left, width, height);
+  }
+
+  // Marks the tiles under a building as free again, once it's gone.
+  pub fn vacate(&mut self, top_left: TilePoint, width: u32, height: u32) {
+    for p in self.tiles_in(top_left, width, height) {
+      self.occupancy[p] = self.occupancy[p].saturating_sub(1);
+    }
+    self.occupancy_changed_in(top_left, width, height);
+  }
+
+  // Changes the tile at p, and its elevation level. Everything we know about
+  // which tiles units can use is worked out again for the whole map, so this
+  // is for editing maps rather than for changing them during a game.
+  pub fn set_tile(&mut self, p: TilePoint, tile: GridTile, level: u8) {
+    if self.width <= p.x || self.height <= p.y {
+      return;
+    }
+    let i = (p.x + p.y * self.width) as usize;
+    self.grid_tiles[i] = tile;
+    self.elevation[i] = level;
+    self.occupancy_changed();
+  }
+
+  // A copy of the map with a new size, keeping its top-left corner where it
+  // is. Any new tiles are empty ground on the lowest level. Buildings aren't
+  // copied over.
+  pub fn resized(&self, width: u32, height: u32) -> Map {
+    let mut grid_tiles = vec![];
+    let mut elevation = vec![];
+    for y in 0..height {
+      for x in 0..width {
+        let p = TilePoint::new(x, y);
+        grid_tiles.push(self.get_tile(p).unwrap_or(GridTile::Empty));
+        elevation.push(self.elevation(p));
+      }
+    }
+    Map::with_elevation(width, height, grid_tiles, elevation)
+  }
+
+  // The clearance of the tile; see Map::clearance. Zero off the map.
+  pub fn clearance(&self, p: TilePoint) -> u8 {
+    if self.width <= p.x || self.height <= p.y {
+      return 0;
+    }
+    self.clearance[(p.x + p.y * self.width) as usize]
+  }
+
+  // Whether something needing the given clearance can stand on the tile.
+  // Anything walkable has clearance at least 1.
+  pub fn has_clearance(&self, p: TilePoint, clearance: u8) -> bool {
+    self.clearance(p) >= clearance
+  }
+
+  // Brings what we know about which tiles units can use up to date.
+  fn occupancy_changed(&mut self) {
+    self.update_clearance();
+    self.update_regions();
+  }
+
+  // Brings what we know about which tiles units can use up to date, after
+  // only the tiles in a rect have been occupied or vacated. Where it can, this
+  // only looks at the area around the rect, since doing the whole map for
+  // every building placed or destroyed is slow on big maps.
+  fn occupancy_changed_in(&mut self, top_left: TilePoint, width: u32, height: u32) {
+    let right = u32::min(top_left.x.saturating_add(width), self.width);
+    let bottom = u32::min(top_left.y.saturating_add(height), self.height);
+    if right <= top_left.x || bottom <= top_left.y {
+      return;
+    }
+    let rect = (top_left.x, top_left.y, right, bottom);
+    self.update_clearance_around(rect);
+    if !self.update_regions_around(rect) {
+      self.update_regions();
+    }
+  }
+
+  // Labels every tile with its region from scratch, by flooding outwards from
+  // each walkable tile that isn't in a region yet.
+  fn update_regions(&mut self) {
+    let mut regions = vec![None; (self.width * self.height) as usize];
+    let mut next_region = 0;
+    for start in self.tiles().map(|item| item.pos) {
+      let i = (start.x + start.y * self.width) as usize;
+      if regions[i].is_some() || !self.is_walkable(start) {
+        continue;
+      }
+      regions[i] = Some(next_region);
+      let mut to_visit = VecDeque::from([start]);
+      while let Some(here) = to_visit.pop_front() {
+        for p in here.neighbors4(self) {
+          let j = (p.x + p.y * self.width) as usize;
+          if regions[j].is_none() && self.is_walkable(p) && self.can_cross(here, p) {
+            regions[j] = Some(next_region);
+            to_visit.push_back(p);
+          }
+        }
+      }
+      next_region += 1;
+    }
+    self.regions = regions;
+  }
+
+  // Fixes up the regions after the tiles in rect (left, top, right, bottom,
+  // with right and bottom just outside it) have been occupied or vacated, by
+  // looking only at the tiles around it. Returns false if that isn't enough to
+  // tell, because a region might have been split in two or joined to another,
+  // in which case the regions need doing from scratch.
+  fn update_regions_around(&mut self, rect: (u32, u32, u32, u32)) -> bool {
+    let (left, top, right, bottom) = rect;
+    let in_rect = |p: TilePoint| left <= p.x && p.x < right && top <= p.y && p.y < bottom;
+    let index = |p: TilePoint| (p.x + p.y * self.width) as usize;
+    let rect_tiles: Vec<TilePoint> = (top..bottom)
+      .flat_map(|y| (left..right).map(move |x| TilePoint::new(x, y)))
+      .collect();
+
+    // Tiles that are still walkable, and the neighbours they can step to, are
+    // in the same region as each other, so each group of them that touches
+    // exactly one region outside the rect goes in that region.
+    let mut labels = vec![];
+    let mut seen = vec![false; rect_tiles.len()];
+    for (n, start) in rect_tiles.iter().enumerate() {
+      if seen[n] || !self.is_walkable(*start) {
+        continue;
+      }
+      seen[n] = true;
+      let mut group = vec![*start];
+      let mut touching = vec![];
+      let mut to_visit = vec![*start];
+      while let Some(here) = to_visit.pop() {
+        touching.extend(self.regions[index(here)]);
+        for p in here.neighbors4(self) {
+          if !self.is_walkable(p) || !self.can_cross(here, p) {
+            continue;
+          }
+          if !in_rect(p) {
+            touching.extend(self.regions[index(p)]);
+            continue;
+          }
+          let m = ((p.x - left) + (p.y - top) * (right - left)) as usize;
+          if !seen[m] {
+            seen[m] = true;
+            group.push(p);
+            to_visit.push(p);
+          }
+        }
+      }
+      touching.sort();
+      touching.dedup();
+      match touching[..] {
+        [region] => labels.extend(group.into_iter().map(|p| (p, region))),
+        _ => return false,
+      }
+    }
+
+    // Anything around the rect that was joined up through it has to still be
+    // joined up without it. It's enough to check that near the rect, within
+    // REGION_SEARCH_MARGIN tiles of it: anything that could walk through the
+    // rect before can walk around it instead.
+    let mut around: Vec<(u32, TilePoint)> = rect_tiles
+      .iter()
+      .filter(|p| !self.is_walkable(**p))
+      .flat_map(|p| p.neighbors4(self))
+      .filter(|p| !in_rect(*p) && self.is_walkable(*p))
+      .filter_map(|p| self.regions[index(p)].map(|region| (region, p)))
+      .collect();
+    around.sort_by_key(|(region, p)| (*region, p.y, p.x));
+    around.dedup();
+    let near_left = left.saturating_sub(REGION_SEARCH_MARGIN);
+    let near_top = top.saturating_sub(REGION_SEARCH_MARGIN);
+    let near_right = u32::min(right + REGION_SEARCH_MARGIN, self.width);
+    let near_bottom = u32::min(bottom + REGION_SEARCH_MARGIN, self.height);
+    let near_index = |p: TilePoint| {
+      (near_left <= p.x && p.x < near_right && near_top <= p.y && p.y < near_bottom)
+        .then(|| ((p.x - near_left) + (p.y - near_top) * (near_right - near_left)) as usize)
+    };
+    let near_size = ((near_right - near_left) * (near_bottom - near_top)) as usize;
+    for group in around.chunk_by(|(a, _), (b, _)| a == b) {
+      let mut reached = vec![false; near_size];
+      let start = group[0].1;
+      reached[near_index(start).unwrap()] = true;
+      let mut to_visit = vec![start];
+      while let Some(here) = to_visit.pop() {
+        for p in here.neighbors4(self) {
+          let i = match near_index(p) {
+            Some(i) if !reached[i] => i,
+            _ => continue,
+          };
+          if self.is_walkable(p) && self.can_cross(here, p) {
+            reached[i] = true;
+            to_visit.push(p);
+          }
+        }
+      }
+      if group.iter().any(|(_, p)| !reached[near_index(*p).unwrap()]) {
+        return false;
+      }
+    }
+
+    for p in rect_tiles {
+      self.regions[index(p)] = None;
+    }
+    for (p, region) in labels {
+      self.regions[index(p)] = Some(region);
+    }
+    true
+  }
+
+  // Works out the clearance of every tile from scratch.
+  fn update_clearance(&mut self) {
+    self.clearance = vec![0; (self.width * self.height) as usize];
+    self.update_clearance_in((0, 0, self.width, self.height));
+  }
+
+  // Fixes up the clearance after the tiles in rect (left, top, right, bottom,
+  // with right and bottom just outside it) have been occupied or vacated.
+  //
+  // A tile can only be affected if its clearance, before or after, reaches
+  // the rect. Clearance goes up by at most one per step, so once there's a
+  // ring around the rect where every tile's clearance is less than its
+  // distance from the rect, nothing outside the ring is affected, and only
+  // the tiles inside it need working out again.
+  fn update_clearance_around(&mut self, rect: (u32, u32, u32, u32)) {
+    let (left, top, right, bottom) = rect;
+    let mut distance = 1;
+    let window = loop {
+      let (l, t) = (left as i64 - distance, top as i64 - distance);
+      let (r, b) = (right as i64 - 1 + distance, bottom as i64 - 1 + distance);
+      let on_map =
+        |x: i64, y: i64| 0 <= x && 0 <= y && x < self.width as i64 && y < self.height as i64;
+      let ring = (l..=r)
+        .flat_map(|x| [(x, t), (x, b)])
+        .chain((t..=b).flat_map(|y| [(l, y), (r, y)]))
+        .filter(|(x, y)| on_map(*x, *y));
+      let mut ring = ring.peekable();
+      if ring.peek().is_none()
+        || ring
+          .all(|(x, y)| (self.clearance[(x + y * self.width as i64) as usize] as i64) < distance)
+      {
+        let clamp = |c: i64, max: u32| c.clamp(0, max as i64) as u32;
+        break (
+          clamp(l + 1, self.width),
+          clamp(t + 1, self.height),
+          clamp(r, self.width),
+          clamp(b, self.height),
+        );
+      }
+      distance += 1;
+    };
+    self.update_clearance_in(window);
+  }
+
+  // Works out the clearance of the tiles in window (left, top, right,
+  // bottom, with right and bottom just outside it), taking the clearance of
+  // the tiles around it as already right. It's done in two sweeps: one down
+  // the window finding the distance to the nearest unwalkable tile above or
+  // to the left, and one back up that also takes the ones below and to the
+  // right into account.
+  fn update_clearance_in(&mut self, window: (u32, u32, u32, u32)) {
+    let (left, top, right, bottom) = window;
+    let (width, height) = (self.width as i64, self.height as i64);
+    let get = |c: &Vec<u8>, x: i64, y: i64| {
+      if x < 0 || y < 0 || width <= x || height <= y {
+        0
+      } else {
+        c[(x + y * width) as usize]
+      }
+    };
+    for y in top as i64..bottom as i64 {
+      for x in left as i64..right as i64 {
+        let i = (x + y * width) as usize;
+        if !self.is_walkable(TilePoint::new(x as u32, y as u32)) {
+          self.clearance[i] = 0;
+          continue;
+        }
+        let nearest = [(-1, 0), (-1, -1), (0, -1), (1, -1)]
+          .iter()
+          .map(|(dx, dy)| get(&self.clearance, x + dx, y + dy))
+          .min()
+          .unwrap();
+        self.clearance[i] = nearest.saturating_add(1);
+      }
+    }
+    for y in (top as i64..bottom as i64).rev() {
+      for x in (left as i64..right as i64).rev() {
+        let i = (x + y * width) as usize;
+        let nearest = [(1, 0), (1, 1), (0, 1), (-1, 1)]
+          .iter()
+          .map(|(dx, dy)| get(&self.clearance, x + dx, y + dy))
+          .min()
+          .unwrap();
+        self.clearance[i] = self.clearance[i].min(nearest.saturating_add(1));
+      }
+    }
+  }
+
+  // Roughly what walking in a straight line between two points costs, in the
+  // same units as GridTile::move_cost.
+  pub fn move_cost_along(&self, from: Point, to: Point) -> Coord {
+    // Checking every quarter tile is close enough.
+    let tiles = (to - from).magnitude() / TILE_WIDTH_COORD;
+    let steps = (tiles * Coord::from_int(4)).floor() + 1;
+    let total: u32 = (0..steps)
+      .map(|i| from + (to - from) * Coord::from_int(2 * i + 1) / Coord::from_int(2 * steps))
+      .map(|p| self.get_tile_at(p).map_or(0, |t| t.move_cost()))
+      .sum();
+    Coord::from_int(total as i64) * tiles / Coord::from_int(steps)
+  }
+
+  // Whether a unit of radius rad could walk in a straight line from one point
+  // to another without its bounding box touching anything unwalkable, or
+  // anything on another elevation level apart from ramps.
+  pub fn can_walk_straight(&self, from: Point, to: Point, rad: Coord) -> bool {
+    if self.get_tile_at(from).is_none() || self.get_tile_at(to).is_none() {
+      return false;
+    }
+    // Units that just touch a wall count as bumping into it, so leave a bit
+    // of room.
+    let margin = rad + Coord::from_int(1);
+    let top_left = Point::new(
+      if from.x < to.x { from.x } else { to.x },
+      if from.y < to.y { from.y } else { to.y },
+    );
+    let swept = Rect {
+      top_left: top_left - Point::new(margin, margin),
+      width: (from.x - to.x).abs() + margin * Coord::from_int(2),
+      height: (from.y - to.y).abs() + margin * Coord::from_int(2),
+    };
+    let level = self.elevation(from.to_tile_point());
+    self
+      .tiles_overlapping_rect(swept)
+      .filter(|item| {
+        !self.is_walkable(item.pos)
+          || (item.tile != GridTile::Ramp && self.elevation(item.pos) != level)
+      })
+      .all(|item| {
+        // The unit's box touches the tile exactly when its center passes
+        // through the tile grown by the size of the box.
+        let grown = Rect {
+          top_left: item.pos.to_world_point() - Point::new(margin, margin),
+          width: TILE_WIDTH_COORD + margin * Coord::from_int(2),
+          height: TILE_WIDTH_COORD + margin * Coord::from_int(2),
+        };
+        !grown.intersects_segment(from, to)
+      })
+  }
+
+  // The indices of the tiles in a rect, leaving out any that are off the map.
+  fn tiles_in(&self, top_left: TilePoint, width: u32, height: u32) -> Vec<usize> {
+    let right = u32::min(top_left.x + width, self.width);
+    let bottom = u32::min(top_left.y + height, self.height);
+    (top_left.y..bottom)
+      .flat_map(|y| (top_left.x..right).map(move |x| (x, y)))
+      .map(|(x, y)| (x + y * self.width) as usize)
+      .collect()
+  }
+
   fn get_tile_unchecked(&self, x: u32, y: u32) -> GridTile {
     self.grid_tiles[(x + y * self.width) as usize]
   }
@@ -131,29 +776,24 @@ impl Map {
 
   fn bounds(&self) -> Rect {
     Rect {
-      top_left: Point::new(Coord(0.), Coord(0.)),
-      width: Coord((self.width * TILE_WIDTH) as f32),
-      height: Coord((self.height * TILE_WIDTH) as f32),
+      top_left: Point::new(Coord::ZERO, Coord::ZERO),
+      width: Coord::from_int((self.width * TILE_WIDTH) as i64),
+      height: Coord::from_int((self.height * TILE_WIDTH) as i64),
     }
   }
 
   // Returns (x, y), a tuple with the coordinates of the tile at this point.
   // May return None if the point is out of bounds.
   fn tile_coords_at(&self, point: Point) -> Option<(u32, u32)> {
-    let (Coord(px), Coord(py)) = (point.x, point.y);
-    if px < 0. || py < 0. {
+    if point.x < Coord::ZERO || point.y < Coord::ZERO {
       return None;
     }
-    let x = px as u32 / TILE_WIDTH;
-    let y = py as u32 / TILE_WIDTH;
-    Some((x, y))
+    Some(self.tile_coords_at_unchecked(point))
   }
 
   fn tile_coords_at_unchecked(&self, point: Point) -> (u32, u32) {
-    let (Coord(px), Coord(py)) = (point.x, point.y);
-    let x = px as u32 / TILE_WIDTH;
-    let y = py as u32 / TILE_WIDTH;
-    (x, y)
+    let point = point.to_tile_point();
+    (point.x, point.y)
   }
 
   pub fn get_tile_at(&self, point: Point) -> Option<GridTile> {
@@ -164,6 +804,18 @@ impl Map {
   }
 }
 
+// The clearance a unit of radius rad needs to stand at the center of a tile
+// without touching anything next to it.
+pub fn clearance_needed(rad: Coord) -> u8 {
+  let overhang = (rad - TILE_WIDTH_COORD / Coord::from_int(2)) / TILE_WIDTH_COORD;
+  let extra_tiles = if overhang < Coord::ZERO {
+    0
+  } else {
+    overhang.floor() as u8 + 1
+  };
+  1 + extra_tiles
+}
+
 pub struct MapTileIterator<'a> {
   x: u32,
   y: u32,
@@ -179,7 +831,7 @@ impl Iterator for MapTileIterator<'_> {
   type Item = MapTileIteratorItem;
 
   fn next(&mut self) -> Option<MapTileIteratorItem> {
-    if self.y >= self.map.height {
+    if self.y >= self.map.height || self.map.width == 0 {
       return None;
     }
     let out = MapTileIteratorItem {
@@ -189,7 +841,7 @@ impl Iterator for MapTileIterator<'_> {
       },
       tile: self.map.get_tile_unchecked(self.x, self.y),
     };
-    if self.x >= self.map.width - 1 {
+    if self.x + 1 >= self.map.width {
       self.x = 0;
       self.y += 1;
     } else {
@@ -268,9 +920,100 @@ impl Iterator for MapTileRectIterator<'_> {
 pub enum GridTile {
   Empty,
   Obstacle,
+  Road,
+  Rough,
+  ShallowWater,
+  DeepWater,
+  Cliff,
+  // Joins two neighbouring elevation levels.
+  Ramp,
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Copy)]
+impl GridTile {
+  // The cheapest move_cost of any tile, for estimating the cost of a path.
+  pub const MIN_MOVE_COST: u32 = 2;
+
+  pub const ALL: [GridTile; 8] = [
+    GridTile::Empty,
+    GridTile::Obstacle,
+    GridTile::Road,
+    GridTile::Rough,
+    GridTile::ShallowWater,
+    GridTile::DeepWater,
+    GridTile::Cliff,
+    GridTile::Ramp,
+  ];
+
+  // The tile a character stands for in a map file:
+  //   O  open ground
+  //   X  wall
+  //   R  road
+  //   B  rough ground
+  //   S  shallow water
+  //   W  deep water
+  //   C  cliff
+  //   ^  ramp
+  pub fn from_char(c: char) -> Option<GridTile> {
+    GridTile::ALL.into_iter().find(|t| t.to_char() == c)
+  }
+
+  // The character that stands for the tile in a map file.
+  pub fn to_char(self) -> char {
+    match self {
+      GridTile::Empty => 'O',
+      GridTile::Obstacle => 'X',
+      GridTile::Road => 'R',
+      GridTile::Rough => 'B',
+      GridTile::ShallowWater => 'S',
+      GridTile::DeepWater => 'W',
+      GridTile::Cliff => 'C',
+      GridTile::Ramp => '^',
+    }
+  }
+
+  pub fn is_walkable(self) -> bool {
+    match self {
+      GridTile::Empty
+      | GridTile::Road
+      | GridTile::Rough
+      | GridTile::ShallowWater
+      | GridTile::Ramp => true,
+      GridTile::Obstacle | GridTile::DeepWater | GridTile::Cliff => false,
+    }
+  }
+
+  // Whether the tile stops anything seeing past it.
+  pub fn is_opaque(self) -> bool {
+    self == GridTile::Obstacle
+  }
+
+  // How fast units move on the tile, as a multiple of their usual speed.
+  pub fn speed_multiplier(self) -> Coord {
+    let (num, den) = match self {
+      GridTile::Road => (3, 2),
+      GridTile::Rough => (3, 5),
+      GridTile::ShallowWater => (1, 2),
+      GridTile::Ramp => (4, 5),
+      _ => (1, 1),
+    };
+    Coord::from_int(num) / Coord::from_int(den)
+  }
+
+  // The cost pathfinding puts on crossing the tile. These are roughly the time
+  // it takes, so units will go a little out of their way to take a road or
+  // avoid wading through water.
+  pub fn move_cost(self) -> u32 {
+    match self {
+      GridTile::Road => 2,
+      GridTile::Rough => 5,
+      GridTile::ShallowWater => 6,
+      GridTile::Ramp => 4,
+      _ => 3,
+    }
+  }
+}
+
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, Debug)]
 pub struct TilePoint {
   x: u32,
   y: u32,
@@ -281,6 +1024,14 @@ impl TilePoint {
     TilePoint { x, y }
   }
 
+  pub fn x(&self) -> u32 {
+    self.x
+  }
+
+  pub fn y(&self) -> u32 {
+    self.y
+  }
+
   // TODO: Optimize to be a custom iterator. That way no malloc needed.
   pub fn neighbors4(&self, map: &Map) -> Vec<TilePoint> {
     let (x, y) = (self.x, self.y);
@@ -302,25 +1053,20 @@ impl TilePoint {
   }
 
   pub fn tile_center(self) -> Point {
-    Point {
-      x: Coord((self.x as f32 + 0.5) * TILE_WIDTH_F32),
-      y: Coord((self.y as f32 + 0.5) * TILE_WIDTH_F32),
-    }
+    self.center_to_world_point()
   }
 
   // Converts tile coordinates to world coordinates.
   pub fn to_world_point(self) -> Point {
     Point {
-      x: Coord((self.x * TILE_WIDTH) as f32),
-      y: Coord((self.y * TILE_WIDTH) as f32),
+      x: Coord::from_int((self.x * TILE_WIDTH) as i64),
+      y: Coord::from_int((self.y * TILE_WIDTH) as i64),
     }
   }
 
   pub fn center_to_world_point(self) -> Point {
-    Point {
-      x: Coord((self.x as f32 + 0.5) * TILE_WIDTH_F32),
-      y: Coord((self.y as f32 + 0.5) * TILE_WIDTH_F32),
-    }
+    let half_tile = Point::new(TILE_WIDTH_COORD, TILE_WIDTH_COORD) / Coord::from_int(2);
+    self.to_world_point() + half_tile
   }
 }
 
@@ -330,11 +1076,11 @@ pub trait ToTilePoint {
 
 impl ToTilePoint for Point {
   fn to_tile_point(self) -> TilePoint {
-    // TODO: Add checks to this and other conversions to ensure
-    // the f32 is inside the range of allowable u32s.
+    // Anything off the top or left of the map is on the edge tiles.
+    let tile = |c: Coord| (c.floor().clamp(0, u32::MAX as i64) as u32) / TILE_WIDTH;
     TilePoint {
-      x: self.x.0 as u32 / TILE_WIDTH,
-      y: self.y.0 as u32 / TILE_WIDTH,
+      x: tile(self.x),
+      y: tile(self.y),
     }
   }
 }
@@ -0,0 +1,686 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::game::PlayerId;
+use crate::map::{GridTile, Map, TilePoint};
+use crate::tiled;
+
+// The first word of every map file in the versioned format.
+const MAGIC: &str = "rts-map";
+// The newest version of the format we can read. Bump it whenever the format
+// changes in a way that older versions of the game couldn't read.
+pub const FORMAT_VERSION: u32 = 1;
+// The most tiles a map can have. Anything bigger is surely a mistake, and would
+// take gigabytes to load.
+pub const MAX_TILES: u64 = 4096 * 4096;
+
+// Everything a map file says about a map: the terrain, plus what's on it when
+// a game starts.
+//
+// A map file starts with a line giving the version of the format it's in,
+// followed by one entry per line:
+//
+//   rts-map 1
+//   name <name>
+//   author <author>
+//   size <width> <height>
+//   start <x> <y>
+//   unit <owner> <x> <y> <unit type>
+//   building <owner> <x> <y> <building type>
+//   resource <x> <y> <amount>
+//   layer tiles
+//   layer elevation
+//
+// Positions are in tiles; for buildings, it's the top-left tile. Each start
+// entry adds a player, numbered from 0 in the order they're listed, who
+// starts at that tile. A layer entry is followed by a row of width characters
+// for each of the height rows of the map. For the tiles layer they're the
+// tile characters listed on GridTile::from_char, and for the elevation
+// layer, digits. Every map needs a tiles layer, and maps without an elevation layer
+// are flat. Blank lines and lines starting with # are ignored.
+//
+// Files that don't start with the version line are in the original format,
+// which only has the terrain; see parse_unversioned.
+#[derive(Clone)]
+pub struct MapFile {
+  pub version: u32,
+  pub name: String,
+  pub author: String,
+  pub start_locations: Vec<TilePoint>,
+  // Along with where in the file each one is, for reporting problems with
+  // them. Entities that didn't come from a file are on line 0.
+  pub entities: Vec<(Location, MapEntity)>,
+  pub map: Map,
+}
+
+// A place in a map file, counting lines and columns from 1.
+#[derive(Clone, Copy, Debug)]
+pub struct Location {
+  pub line: usize,
+  pub col: usize,
+}
+
+// Something wrong with a map file.
+#[derive(Debug)]
+pub enum MapError {
+  // The file couldn't be read.
+  Io {
+    path: String,
+    error: std::io::Error,
+  },
+  // Something at a particular place in the file is wrong.
+  Parse {
+    path: String,
+    line: usize,
+    col: usize,
+    message: String,
+  },
+  // Something the file has to have isn't there.
+  Missing {
+    path: String,
+    what: &'static str,
+  },
+  // Something is wrong that can't be pinned to a place in the file, because
+  // it's in a format that doesn't say where things are.
+  Invalid {
+    path: String,
+    message: String,
+  },
+}
+
+impl MapEntity {
+  pub fn pos(&self) -> TilePoint {
+    match self {
+      MapEntity::Unit { pos, .. }
+      | MapEntity::Building { pos, .. }
+      | MapEntity::ResourceNode { pos, .. } => *pos,
+    }
+  }
+
+  // The player it belongs to. Resource nodes don't belong to anyone.
+  pub fn owner(&self) -> Option<PlayerId> {
+    match self {
+      MapEntity::Unit { owner, .. } | MapEntity::Building { owner, .. } => Some(*owner),
+      MapEntity::ResourceNode { .. } => None,
+    }
+  }
+}
+
+impl MapError {
+  pub fn at(path: &str, loc: Location, message: String) -> MapError {
+    MapError::Parse {
+      path: path.to_string(),
+      line: loc.line,
+      col: loc.col,
+      message,
+    }
+  }
+}
+
+impl fmt::Display for MapError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MapError::Io { path, error } => write!(f, "{}: {}", path, error),
+      MapError::Parse {
+        path,
+        line,
+        col,
+        message,
+      } => write!(f, "{}:{}:{}: {}", path, line, col, message),
+      MapError::Missing { path, what } => write!(f, "{}: map has no {}", path, what),
+      MapError::Invalid { path, message } => write!(f, "{}: {}", path, message),
+    }
+  }
+}
+
+// Something placed on the map before the game starts. Units and buildings
+// refer to their types by name.
+#[derive(Clone)]
+pub enum MapEntity {
+  Unit {
+    unit_type: String,
+    owner: PlayerId,
+    pos: TilePoint,
+  },
+  Building {
+    building_type: String,
+    owner: PlayerId,
+    pos: TilePoint,
+  },
+  ResourceNode {
+    pos: TilePoint,
+    amount: u32,
+  },
+}
+
+impl MapFile {
+  // Reads a map file in any of the formats, including maps made in Tiled.
+  pub fn from_file(path: &str) -> Result<MapFile, MapError> {
+    if tiled::is_tiled_map(path) {
+      return tiled::import(path, tiled::DEFAULT_TERRAIN_PROPERTY);
+    }
+    let file = std::fs::read_to_string(path).map_err(|error| MapError::Io {
+      path: path.to_string(),
+      error,
+    })?;
+    if !file.starts_with(MAGIC) {
+      return Ok(MapFile {
+        version: 0,
+        name: path.to_string(),
+        author: String::new(),
+        start_locations: vec![],
+        entities: vec![],
+        map: parse_unversioned(path, &file)?,
+      });
+    }
+    MapFile::parse(path, &file)
+  }
+
+  // Reads a map in the versioned format. The path is only used in errors.
+  pub fn parse(path: &str, text: &str) -> Result<MapFile, MapError> {
+    let end = end_of(text);
+    let mut lines = text
+      .lines()
+      .enumerate()
+      .map(|(i, line)| (i + 1, line.trim_end()))
+      .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+    let (n, first) = lines.next().ok_or(MapError::Missing {
+      path: path.to_string(),
+      what: "version line",
+    })?;
+    let mut entry = Entry::new(path, n, first);
+    let magic_loc = entry.loc();
+    if entry.next() != Some(MAGIC) {
+      return Err(MapError::at(
+        path,
+        magic_loc,
+        format!("expected \"{} <version>\"", MAGIC),
+      ));
+    }
+    let version_loc = entry.loc();
+    let version: u32 = entry.field("version")?;
+    entry.end()?;
+    if version == 0 || version > FORMAT_VERSION {
+      return Err(MapError::at(
+        path,
+        version_loc,
+        format!(
+          "map is in version {} of the format, but the newest supported is version {}",
+          version, FORMAT_VERSION
+        ),
+      ));
+    }
+
+    let mut name = String::new();
+    let mut author = String::new();
+    let mut size = None;
+    let mut start_locations = vec![];
+    let mut entities = vec![];
+    let mut tiles = None;
+    let mut elevation = None;
+    while let Some((n, line)) = lines.next() {
+      let mut entry = Entry::new(path, n, line);
+      let key_loc = entry.loc();
+      let key = entry.next().unwrap();
+      match key {
+        "name" => name = entry.rest().unwrap_or("").to_string(),
+        "author" => author = entry.rest().unwrap_or("").to_string(),
+        "size" => {
+          if size.is_some() {
+            return Err(MapError::at(
+              path,
+              key_loc,
+              "size is given more than once".to_string(),
+            ));
+          }
+          if tiles.is_some() || elevation.is_some() {
+            return Err(MapError::at(
+              path,
+              key_loc,
+              "size comes after a layer".to_string(),
+            ));
+          }
+          let width: u32 = entry.field("width")?;
+          let height: u32 = entry.field("height")?;
+          if width == 0 || height == 0 {
+            return Err(MapError::at(
+              path,
+              key_loc,
+              format!("a map can't be {}x{}", width, height),
+            ));
+          }
+          size = Some((width, height));
+        }
+        "start" => {
+          let loc = entry.loc();
+          let p = entry.point()?;
+          start_locations.push((loc, p));
+        }
+        "unit" | "building" => {
+          let owner = entry.field("owner")?;
+          let pos = entry.point()?;
+          // Type names can have spaces in them, so they take up the rest of
+          // the line.
+          let type_loc = entry.loc();
+          let type_name = entry
+            .rest()
+            .ok_or(MapError::at(
+              path,
+              type_loc,
+              format!("expected {} type", key),
+            ))?
+            .to_string();
+          entities.push((
+            type_loc,
+            if key == "unit" {
+              MapEntity::Unit {
+                unit_type: type_name,
+                owner,
+                pos,
+              }
+            } else {
+              MapEntity::Building {
+                building_type: type_name,
+                owner,
+                pos,
+              }
+            },
+          ));
+        }
+        "resource" => {
+          let pos = entry.point()?;
+          let amount = entry.field("amount")?;
+          entities.push((key_loc, MapEntity::ResourceNode { pos, amount }));
+        }
+        "layer" => {
+          let (width, height) = size.ok_or(MapError::at(
+            path,
+            key_loc,
+            "layer comes before size".to_string(),
+          ))?;
+          let layer_loc = entry.loc();
+          let layer = entry.field::<String>("layer name")?;
+          entry.end()?;
+          if layer != "tiles" && layer != "elevation" {
+            return Err(MapError::at(
+              path,
+              layer_loc,
+              format!("unknown layer {:?}", layer),
+            ));
+          }
+          let given = if layer == "tiles" {
+            tiles.is_some()
+          } else {
+            elevation.is_some()
+          };
+          if given {
+            return Err(MapError::at(
+              path,
+              layer_loc,
+              format!("layer {:?} is given more than once", layer),
+            ));
+          }
+          let rows = read_rows(path, &mut lines, width, height, end)?;
+          if layer == "tiles" {
+            tiles = Some(parse_grid(path, &rows, "tile", GridTile::from_char)?);
+          } else {
+            elevation = Some(parse_grid(path, &rows, "elevation", elevation_level)?);
+          }
+        }
+        _ => {
+          return Err(MapError::at(
+            path,
+            key_loc,
+            format!("unknown entry {:?}", key),
+          ))
+        }
+      }
+      entry.end()?;
+    }
+
+    let missing = |what| MapError::Missing {
+      path: path.to_string(),
+      what,
+    };
+    let (width, height) = size.ok_or(missing("size"))?;
+    let tiles = tiles.ok_or(missing("tiles layer"))?;
+    let elevation = elevation.unwrap_or(vec![0; tiles.len()]);
+    // read_rows makes sure of this, but Map::with_elevation panics if it's
+    // wrong, so it's worth being certain.
+    let area = width as usize * height as usize;
+    if tiles.len() != area || elevation.len() != area {
+      return Err(MapError::Invalid {
+        path: path.to_string(),
+        message: format!("layers don't match the map's size of {}x{}", width, height),
+      });
+    }
+    let map = Map::with_elevation(width, height, tiles, elevation);
+
+    // Everything has to be on the map, and belong to one of its players.
+    let on_map = |p: TilePoint| map.get_tile(p).is_some();
+    for (loc, p) in start_locations.iter() {
+      if !on_map(*p) {
+        return Err(MapError::at(
+          path,
+          *loc,
+          format!("start location ({}, {}) is off the map", p.x(), p.y()),
+        ));
+      }
+    }
+    for (loc, entity) in entities.iter() {
+      let (pos, owner) = (entity.pos(), entity.owner());
+      if !on_map(pos) {
+        return Err(MapError::at(
+          path,
+          *loc,
+          format!("({}, {}) is off the map", pos.x(), pos.y()),
+        ));
+      }
+      if owner.is_some_and(|owner| owner as usize >= start_locations.len()) {
+        return Err(MapError::at(
+          path,
+          *loc,
+          format!(
+            "belongs to player {}, but the map only has {} start locations",
+            owner.unwrap(),
+            start_locations.len()
+          ),
+        ));
+      }
+    }
+
+    Ok(MapFile {
+      version,
+      name,
+      author,
+      start_locations: start_locations.into_iter().map(|(_, p)| p).collect(),
+      entities,
+      map,
+    })
+  }
+
+  // Writes the map out in the newest version of the format.
+  pub fn to_text(&self) -> String {
+    let mut out = format!("{} {}\n", MAGIC, FORMAT_VERSION);
+    if !self.name.is_empty() {
+      out += &format!("name {}\n", self.name);
+    }
+    if !self.author.is_empty() {
+      out += &format!("author {}\n", self.author);
+    }
+    out += &format!("size {} {}\n", self.map.width, self.map.height);
+    for p in self.start_locations.iter() {
+      out += &format!("start {} {}\n", p.x(), p.y());
+    }
+    for (_, entity) in self.entities.iter() {
+      out += &match entity {
+        MapEntity::Unit {
+          unit_type,
+          owner,
+          pos,
+        } => format!("unit {} {} {} {}\n", owner, pos.x(), pos.y(), unit_type),
+        MapEntity::Building {
+          building_type,
+          owner,
+          pos,
+        } => format!(
+          "building {} {} {} {}\n",
+          owner,
+          pos.x(),
+          pos.y(),
+          building_type
+        ),
+        MapEntity::ResourceNode { pos, amount } => {
+          format!("resource {} {} {}\n", pos.x(), pos.y(), amount)
+        }
+      };
+    }
+
+    let rows = |cell: &dyn Fn(TilePoint) -> char| {
+      let mut rows = String::new();
+      for y in 0..self.map.height {
+        rows.extend((0..self.map.width).map(|x| cell(TilePoint::new(x, y))));
+        rows.push('\n');
+      }
+      rows
+    };
+    out += "layer tiles\n";
+    out += &rows(&|p| self.map.get_tile(p).unwrap().to_char());
+    if self
+      .map
+      .tiles()
+      .any(|item| self.map.elevation(item.pos) != 0)
+    {
+      out += "layer elevation\n";
+      out += &rows(&|p| char::from_digit(self.map.elevation(p) as u32, 10).unwrap());
+    }
+    out
+  }
+}
+
+// Reads a map in the original format, from before the format had versions.
+// It's like so:
+//
+//   WIDTH
+//   HEIGHT
+//   TILES
+//   ELEVATION (optional)
+//
+// TILES is a grid of WIDTH by HEIGHT tile characters; see GridTile::from_char.
+// ELEVATION is a grid the same size, giving the elevation level of each tile
+// as a digit. Without it, the map is flat.
+pub fn parse_unversioned(path: &str, text: &str) -> Result<Map, MapError> {
+  let end = end_of(text);
+  let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+  let mut read_number = |what: &str| -> Result<u32, MapError> {
+    let (n, line) = lines.next().unwrap_or((end.line, ""));
+    let loc = Location { line: n, col: 1 };
+    if line.is_empty() {
+      return Err(MapError::at(path, loc, format!("expected {}", what)));
+    }
+    let number = line
+      .parse()
+      .map_err(|_| MapError::at(path, loc, format!("failed to parse {} {:?}", what, line)))?;
+    if number == 0 {
+      return Err(MapError::at(path, loc, format!("{} can't be 0", what)));
+    }
+    Ok(number)
+  };
+  let width = read_number("WIDTH")?;
+  let height = read_number("HEIGHT")?;
+
+  let rows = read_rows(path, &mut lines, width, height, end)?;
+  let grid_tiles = parse_grid(path, &rows, "tile", GridTile::from_char)?;
+
+  // Only blank lines may follow the map.
+  let mut lines = lines.skip_while(|(_, line)| line.is_empty()).peekable();
+  let elevation = if lines.peek().is_some() {
+    let rows = read_rows(path, &mut lines, width, height, end)?;
+    parse_grid(path, &rows, "elevation", elevation_level)?
+  } else {
+    vec![0; grid_tiles.len()]
+  };
+  if let Some((n, line)) = lines.find(|(_, line)| !line.trim().is_empty()) {
+    return Err(MapError::at(
+      path,
+      Location { line: n, col: 1 },
+      format!("unexpected {:?} after the end of the map", line),
+    ));
+  }
+
+  Ok(Map::with_elevation(width, height, grid_tiles, elevation))
+}
+
+// Where a map file runs out, for reporting things missing from the end of it.
+fn end_of(text: &str) -> Location {
+  Location {
+    line: text.lines().count() + 1,
+    col: 1,
+  }
+}
+
+fn elevation_level(c: char) -> Option<u8> {
+  c.to_digit(10).map(|level| level as u8)
+}
+
+// One line of a map file in the versioned format, split into fields on
+// whitespace.
+struct Entry<'a> {
+  path: &'a str,
+  line: usize,
+  text: &'a str,
+  // Each field, along with the column and byte offset it starts at.
+  fields: Vec<(usize, usize, &'a str)>,
+  // The index of the next field to read.
+  next: usize,
+}
+
+impl<'a> Entry<'a> {
+  fn new(path: &'a str, line: usize, text: &'a str) -> Entry<'a> {
+    let mut fields = vec![];
+    let mut start = None;
+    for (col, (i, c)) in text.char_indices().enumerate() {
+      match (start, c.is_whitespace()) {
+        (None, false) => start = Some((col + 1, i)),
+        (Some((col, from)), true) => {
+          fields.push((col, from, &text[from..i]));
+          start = None;
+        }
+        _ => {}
+      }
+    }
+    if let Some((col, from)) = start {
+      fields.push((col, from, &text[from..]));
+    }
+    Entry {
+      path,
+      line,
+      text,
+      fields,
+      next: 0,
+    }
+  }
+
+  // Where the next field starts, or the end of the line if there isn't one.
+  fn loc(&self) -> Location {
+    let col = match self.fields.get(self.next) {
+      Some((col, _, _)) => *col,
+      None => self.text.chars().count() + 1,
+    };
+    Location {
+      line: self.line,
+      col,
+    }
+  }
+
+  fn next(&mut self) -> Option<&'a str> {
+    let field = self.fields.get(self.next)?.2;
+    self.next += 1;
+    Some(field)
+  }
+
+  // Reads the next field as a T.
+  fn field<T: FromStr>(&mut self, what: &str) -> Result<T, MapError> {
+    let loc = self.loc();
+    let field = self
+      .next()
+      .ok_or(MapError::at(self.path, loc, format!("expected {}", what)))?;
+    field.parse().map_err(|_| {
+      MapError::at(
+        self.path,
+        loc,
+        format!("failed to parse {} {:?}", what, field),
+      )
+    })
+  }
+
+  fn point(&mut self) -> Result<TilePoint, MapError> {
+    let x = self.field("x")?;
+    let y = self.field("y")?;
+    Ok(TilePoint::new(x, y))
+  }
+
+  // Everything from the next field to the end of the line.
+  fn rest(&mut self) -> Option<&'a str> {
+    let from = self.fields.get(self.next)?.1;
+    self.next = self.fields.len();
+    Some(&self.text[from..])
+  }
+
+  // Checks that every field has been read.
+  fn end(&self) -> Result<(), MapError> {
+    match self.fields.get(self.next) {
+      Some((col, _, field)) => Err(MapError::at(
+        self.path,
+        Location {
+          line: self.line,
+          col: *col,
+        },
+        format!("unexpected {:?} at the end of the line", field),
+      )),
+      None => Ok(()),
+    }
+  }
+}
+
+// Reads the height rows of a grid of characters, checking that there are
+// enough of them and that each is width characters long.
+fn read_rows<'a>(
+  path: &str,
+  lines: &mut impl Iterator<Item = (usize, &'a str)>,
+  width: u32,
+  height: u32,
+  end: Location,
+) -> Result<Vec<(usize, &'a str)>, MapError> {
+  let mut rows = vec![];
+  for i in 0..height {
+    let (n, row) = lines.next().ok_or(MapError::at(
+      path,
+      end,
+      format!("map ends at row {} of expected height {}", i, height),
+    ))?;
+    let length = row.chars().count();
+    if length != width as usize {
+      return Err(MapError::at(
+        path,
+        Location {
+          line: n,
+          col: length.min(width as usize) + 1,
+        },
+        format!(
+          "row has length {}, when it should be width {}",
+          length, width
+        ),
+      ));
+    }
+    rows.push((n, row));
+  }
+  Ok(rows)
+}
+
+// Turns each character of a grid into a T, or fails at the first that
+// doesn't stand for one.
+fn parse_grid<T>(
+  path: &str,
+  rows: &[(usize, &str)],
+  what: &str,
+  parse: impl Fn(char) -> Option<T>,
+) -> Result<Vec<T>, MapError> {
+  let mut grid = vec![];
+  for (n, row) in rows {
+    for (col, c) in row.chars().enumerate() {
+      let item = parse(c).ok_or(MapError::at(
+        path,
+        Location {
+          line: *n,
+          col: col + 1,
+        },
+        format!("unknown {} {:?}", what, c),
+      ))?;
+      grid.push(item);
+    }
+  }
+  Ok(grid)
+}
@@ -0,0 +1,344 @@
+use std::f64::consts::TAU;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::game::PlayerId;
+use crate::map::{GridTile, Map, TilePoint};
+use crate::map_file::{Location, MapEntity, MapFile, FORMAT_VERSION, MAX_TILES};
+
+// How far out from each start location the wall around the base is, in tiles.
+const BASE_RADIUS: f64 = 7.;
+// How much room is cleared around each start location for the base.
+const BASE_CLEARING: f64 = 4.;
+// How wide the way out of each base is, in tiles.
+const CHOKEPOINT_WIDTH: f64 = 3.;
+// How many times the random obstacles are smoothed into blobs.
+const SMOOTHING_PASSES: u32 = 4;
+// How much each resource node holds.
+const RESOURCE_NODE_AMOUNT: u32 = 500;
+
+pub struct MapGenSettings {
+  pub seed: u64,
+  pub players: u32,
+  pub width: u32,
+  pub height: u32,
+  // Roughly how much of the map starts out as obstacles, before they're
+  // smoothed into blobs and bases are cleared out of them.
+  pub obstacle_density: f64,
+}
+
+impl MapGenSettings {
+  pub fn new(seed: u64, players: u32) -> MapGenSettings {
+    MapGenSettings {
+      seed,
+      players,
+      width: 64,
+      height: 64,
+      obstacle_density: 0.45,
+    }
+  }
+}
+
+// Makes a map for the given number of players. The same settings always make
+// the same map.
+//
+// The map has rotational symmetry: one wedge of it is made, with a base for
+// the first player, and the others are copies of it turned around the center
+// of the map. Each base has a wall around it with a chokepoint leading towards
+// the middle. For two players, or four on a square map, every player's
+// surroundings are exactly the same. Otherwise they're as close as the grid
+// allows.
+//
+// Every start location and resource node can be reached from every other.
+// Where rounding off the copies blocks the way, a path is carved through.
+//
+// Fails if there are more players than there are player ids, if the map is
+// too big, or if it's too small for everyone to have a base of their own.
+pub fn generate(settings: &MapGenSettings) -> Result<MapFile, String> {
+  let mut rng = StdRng::seed_from_u64(settings.seed);
+  let (width, height) = (settings.width.max(1), settings.height.max(1));
+  let players = settings.players.max(1);
+  if width
+    .checked_mul(height)
+    .is_none_or(|area| area as u64 > MAX_TILES)
+  {
+    return Err(format!(
+      "can't make a {}x{} map; the most tiles a map can have is {}",
+      width, height, MAX_TILES
+    ));
+  }
+  if players > PlayerId::MAX as u32 + 1 {
+    return Err(format!(
+      "can't make a map for {} players; the most there can be is {}",
+      players,
+      PlayerId::MAX as u32 + 1
+    ));
+  }
+  let center = ((width as f64 - 1.) / 2., (height as f64 - 1.) / 2.);
+  let sector = TAU / players as f64;
+  let first_angle = rng.gen_range(0. ..TAU);
+  let index = |(x, y): (i64, i64)| (x + y * width as i64) as usize;
+
+  // Random obstacles, smoothed into blobs by making each tile whatever most
+  // of its neighbours are.
+  let mut base: Vec<GridTile> = (0..width * height)
+    .map(|_| {
+      if rng.gen_bool(settings.obstacle_density.clamp(0., 1.)) {
+        GridTile::Obstacle
+      } else {
+        GridTile::Empty
+      }
+    })
+    .collect();
+  for _ in 0..SMOOTHING_PASSES {
+    let before = base.clone();
+    for (x, y) in tiles(width, height) {
+      let blocked_neighbours = (-1..=1)
+        .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+        .filter(|q| *q != (x, y) && in_bounds(*q, width, height))
+        .filter(|q| before[index(*q)] == GridTile::Obstacle)
+        .count();
+      if blocked_neighbours >= 5 {
+        base[index((x, y))] = GridTile::Obstacle;
+      } else if blocked_neighbours <= 3 {
+        base[index((x, y))] = GridTile::Empty;
+      }
+    }
+  }
+
+  // Some patches of rough ground in the open.
+  for _ in 0..(width * height) / 400 {
+    let patch = (
+      rng.gen_range(0. ..width as f64),
+      rng.gen_range(0. ..height as f64),
+    );
+    let radius = rng.gen_range(1.5..3.5);
+    for p in tiles(width, height) {
+      if distance(to_f64(p), patch) <= radius && base[index(p)] == GridTile::Empty {
+        base[index(p)] = GridTile::Rough;
+      }
+    }
+  }
+
+  // The first player's base, out towards the edge of the map, walled in
+  // except for a chokepoint facing the middle.
+  let start_distance = 0.38 * width.min(height) as f64;
+  let start = (
+    center.0 + start_distance * first_angle.cos(),
+    center.1 + start_distance * first_angle.sin(),
+  );
+  let inwards = (center.1 - start.1).atan2(center.0 - start.0);
+  let gap = (CHOKEPOINT_WIDTH / 2.).atan2(BASE_RADIUS);
+  for p in tiles(width, height) {
+    let d = distance(to_f64(p), start);
+    let angle = (p.1 as f64 - start.1).atan2(p.0 as f64 - start.0);
+    if d < BASE_CLEARING {
+      base[index(p)] = GridTile::Empty;
+    } else if (BASE_RADIUS..BASE_RADIUS + 1.5).contains(&d) && angle_between(angle, inwards) > gap {
+      base[index(p)] = GridTile::Obstacle;
+    }
+  }
+
+  // Resources: a few around the base, on the side away from the middle, and
+  // one further out for expanding to.
+  let mut resources = vec![];
+  for offset in [-0.9, -0.3, 0.3, 0.9] {
+    let angle = inwards + std::f64::consts::PI + offset;
+    resources.push((start.0 + 3. * angle.cos(), start.1 + 3. * angle.sin()));
+  }
+  let expansion_angle = first_angle + 0.3 * sector;
+  let expansion_distance = 0.22 * width.min(height) as f64;
+  let expansion = (
+    center.0 + expansion_distance * expansion_angle.cos(),
+    center.1 + expansion_distance * expansion_angle.sin(),
+  );
+  resources.push(expansion);
+
+  // Ways from the base and the expansion to the middle of the map, which is
+  // kept clear so that everyone's ways meet there.
+  carve(
+    &mut base,
+    width,
+    height,
+    start,
+    center,
+    CHOKEPOINT_WIDTH / 2.,
+  );
+  carve(&mut base, width, height, expansion, center, 1.);
+  carve(&mut base, width, height, expansion, expansion, 2.);
+  carve(&mut base, width, height, center, center, 3.);
+
+  // Copy the first player's wedge around the map.
+  let canonical = |p: (i64, i64)| {
+    let (x, y) = (p.0 as f64 - center.0, p.1 as f64 - center.1);
+    let turns = ((y.atan2(x) - first_angle) / sector).round();
+    let (rx, ry) = rotate((x, y), -turns * sector);
+    let q = (
+      (center.0 + rx).round() as i64,
+      (center.1 + ry).round() as i64,
+    );
+    (
+      q.0.clamp(0, width as i64 - 1),
+      q.1.clamp(0, height as i64 - 1),
+    )
+  };
+  let mut grid: Vec<GridTile> = tiles(width, height)
+    .map(|p| base[index(canonical(p))])
+    .collect();
+  let for_player = |p: (f64, f64), player: u32| {
+    let (x, y) = rotate((p.0 - center.0, p.1 - center.1), player as f64 * sector);
+    let q = ((center.0 + x).round(), (center.1 + y).round());
+    TilePoint::new(
+      (q.0.max(0.) as u32).min(width - 1),
+      (q.1.max(0.) as u32).min(height - 1),
+    )
+  };
+  let start_locations: Vec<TilePoint> = (0..players).map(|i| for_player(start, i)).collect();
+  let resource_nodes: Vec<TilePoint> = (0..players)
+    .flat_map(|i| resources.iter().map(move |p| for_player(*p, i)))
+    .collect();
+  // The town hall goes just behind the start location, away from the middle.
+  let behind = (start.0 - 2. * inwards.cos(), start.1 - 2. * inwards.sin());
+  let town_halls: Vec<TilePoint> = (0..players).map(|i| for_player(behind, i)).collect();
+  // On a small enough map, rounding puts bases on top of each other.
+  let bases: Vec<TilePoint> = start_locations
+    .iter()
+    .chain(town_halls.iter())
+    .copied()
+    .collect();
+  if (1..bases.len()).any(|i| bases[..i].contains(&bases[i])) {
+    return Err(format!(
+      "a {}x{} map is too small for {} players",
+      width, height, players
+    ));
+  }
+
+  // Make sure everything can be got to from the first start location.
+  for p in start_locations
+    .iter()
+    .chain(resource_nodes.iter())
+    .chain(town_halls.iter())
+  {
+    grid[(p.x() + p.y() * width) as usize] = GridTile::Empty;
+  }
+  let first = start_locations[0];
+  let map = loop {
+    let map = Map::new(width, height, grid.clone());
+    let cut_off = start_locations
+      .iter()
+      .chain(resource_nodes.iter())
+      .find(|p| !map.is_reachable(first, **p));
+    match cut_off {
+      Some(p) => carve(
+        &mut grid,
+        width,
+        height,
+        (p.x() as f64, p.y() as f64),
+        (first.x() as f64, first.y() as f64),
+        1.,
+      ),
+      None => break map,
+    }
+  };
+
+  let mut entities = vec![];
+  let location = Location { line: 0, col: 0 };
+  for (i, p) in start_locations.iter().enumerate() {
+    entities.push((
+      location,
+      MapEntity::Building {
+        building_type: "Town Hall".to_string(),
+        owner: i as u8,
+        pos: town_halls[i],
+      },
+    ));
+    entities.push((
+      location,
+      MapEntity::Unit {
+        unit_type: "Newt".to_string(),
+        owner: i as u8,
+        pos: *p,
+      },
+    ));
+  }
+  // On maps that aren't symmetric on the grid, rounding can put two things on
+  // the same tile, in which case the resource node is left out.
+  for p in resource_nodes {
+    if entities.iter().any(|(_, e)| e.pos() == p) {
+      continue;
+    }
+    entities.push((
+      location,
+      MapEntity::ResourceNode {
+        pos: p,
+        amount: RESOURCE_NODE_AMOUNT,
+      },
+    ));
+  }
+
+  Ok(MapFile {
+    version: FORMAT_VERSION,
+    name: format!("Generated {}-player map (seed {})", players, settings.seed),
+    author: "rts map generator".to_string(),
+    start_locations,
+    entities,
+    map,
+  })
+}
+
+// Every tile position on a map of the given size, as signed coordinates so
+// that neighbours off the map can be worked out too.
+fn tiles(width: u32, height: u32) -> impl Iterator<Item = (i64, i64)> {
+  (0..height as i64).flat_map(move |y| (0..width as i64).map(move |x| (x, y)))
+}
+
+fn in_bounds((x, y): (i64, i64), width: u32, height: u32) -> bool {
+  0 <= x && x < width as i64 && 0 <= y && y < height as i64
+}
+
+fn to_f64((x, y): (i64, i64)) -> (f64, f64) {
+  (x as f64, y as f64)
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+  (a.0 - b.0).hypot(a.1 - b.1)
+}
+
+fn rotate((x, y): (f64, f64), angle: f64) -> (f64, f64) {
+  let (sin, cos) = angle.sin_cos();
+  (x * cos - y * sin, x * sin + y * cos)
+}
+
+// The smallest angle between two directions.
+fn angle_between(a: f64, b: f64) -> f64 {
+  let d = (a - b).rem_euclid(TAU);
+  d.min(TAU - d)
+}
+
+// Clears every tile within radius of the line segment from a to b. A radius
+// of at least 1 leaves a way through that units can walk along.
+fn carve(
+  grid: &mut [GridTile],
+  width: u32,
+  height: u32,
+  a: (f64, f64),
+  b: (f64, f64),
+  radius: f64,
+) {
+  let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+  let length_squared = dx * dx + dy * dy;
+  for p in tiles(width, height) {
+    let p_f = to_f64(p);
+    let t = if length_squared == 0. {
+      0.
+    } else {
+      (((p_f.0 - a.0) * dx + (p_f.1 - a.1) * dy) / length_squared).clamp(0., 1.)
+    };
+    let closest = (a.0 + t * dx, a.1 + t * dy);
+    let i = (p.0 + p.1 * width as i64) as usize;
+    if distance(p_f, closest) <= radius && !grid[i].is_walkable() {
+      grid[i] = GridTile::Empty;
+    }
+  }
+}
@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::flow_field::FlowFieldCache;
+use crate::hpa::PathGraph;
+use crate::map::{Map, TilePoint};
+
+// Everything units use to find their way around the map. A unit on its own
+// gets its path from the HPA* graph, which is cheap even on big maps. A group
+// all heading to the same place shares a flow field instead, so the group
+// pays for one search however many units are in it. Either way the path is
+// worked out once, as a list of tiles for the unit to walk through, rather
+// than being looked up again every tick.
+//
+// Bigger units can't fit through the gaps smaller ones can, so paths are found
+// for the clearance the unit needs; see Map::clearance.
+pub struct Pathfinder {
+  pub flow_fields: FlowFieldCache,
+  // HPA* graphs by the clearance they're for. The graph for the smallest units
+  // is built up front, and the others when a unit first needs them.
+  graphs: HashMap<u8, PathGraph>,
+}
+
+impl Pathfinder {
+  pub fn new(map: &Map) -> Pathfinder {
+    let mut graphs = HashMap::new();
+    graphs.insert(1, PathGraph::new(map, 1));
+    Pathfinder {
+      flow_fields: FlowFieldCache::new(),
+      graphs,
+    }
+  }
+
+  // Finds a path from src to dest. Returns every tile along the way, including
+  // both ends. If there's no way to dest, the path goes as close as it can get
+  // instead. Returns None only if src isn't on the map.
+  pub fn find_path(
+    &mut self,
+    map: &Map,
+    src: TilePoint,
+    dest: TilePoint,
+    via: Option<TilePoint>,
+    clearance: u8,
+  ) -> Option<Vec<TilePoint>> {
+    // The map knows which tiles are connected, so we only search for a path to
+    // dest when there's a chance of one. Units next to an unwalkable dest are
+    // as close as they can get, so a dest counts as reachable if any of its
+    // neighbours are. And units stuck somewhere unwalkable aren't in any
+    // region, so they always search.
+    let may_reach = map.region(src).is_none()
+      || map.is_reachable(src, dest)
+      || (!map.is_walkable(dest)
+        && dest
+          .neighbors4(map)
+          .into_iter()
+          .any(|p| map.is_reachable(src, p)));
+    if may_reach {
+      if let Some(path) = self.find_exact_path(map, src, dest, via, clearance) {
+        return Some(path);
+      }
+    }
+    let closest = self.nearest_reachable(map, src, dest, clearance)?;
+    self.find_exact_path(map, src, closest, None, clearance)
+  }
+
+  // Finds a path from src to dest, or None if there's no way there.
+  //
+  // Units moving as a group each head for their own spot near the group's
+  // destination, so they pass that destination as via to share its flow
+  // field. We follow the field from src and from dest until the two trails
+  // meet, then walk back out along dest's trail.
+  fn find_exact_path(
+    &mut self,
+    map: &Map,
+    src: TilePoint,
+    dest: TilePoint,
+    via: Option<TilePoint>,
+    clearance: u8,
+  ) -> Option<Vec<TilePoint>> {
+    let via = match via {
+      Some(via) => via,
+      None => {
+        return self
+          .graphs
+          .entry(clearance)
+          .or_insert_with(|| PathGraph::new(map, clearance))
+          .find_path(map, src, dest)
+      }
+    };
+    let field = self.flow_fields.get(map, via, clearance);
+    let to_via = field.path_from(map, src)?;
+    let from_dest = field.path_from(map, dest)?;
+
+    // Both trails end at via, so they always meet somewhere.
+    let on_dest_trail: HashMap<TilePoint, usize> =
+      from_dest.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+    let (meet, dest_meet) = to_via
+      .iter()
+      .enumerate()
+      .find_map(|(i, p)| on_dest_trail.get(p).map(|j| (i, *j)))
+      .unwrap();
+    let mut path = to_via[..=meet].to_vec();
+    path.extend(from_dest[..dest_meet].iter().rev());
+    Some(path)
+  }
+
+  // The tile closest to dest, as the crow flies, that a unit at src could get
+  // to. For units bigger than a tile, or stuck somewhere unwalkable, it's the
+  // one the fewest steps from src out of those equally close.
+  fn nearest_reachable(
+    &self,
+    map: &Map,
+    src: TilePoint,
+    dest: TilePoint,
+    clearance: u8,
+  ) -> Option<TilePoint> {
+    map.get_tile(src)?;
+    if clearance == 1 && map.region(src).is_some() {
+      // The smallest units can go anywhere in src's region.
+      return map.nearest_tile(dest, |p| map.is_reachable(src, p));
+    }
+    let distance = |p: TilePoint| {
+      let (dx, dy) = (p.x().abs_diff(dest.x()), p.y().abs_diff(dest.y()));
+      dx * dx + dy * dy
+    };
+    // Searching outwards from src finds nearer tiles first, so only replacing
+    // the best tile with strictly closer ones breaks ties the right way.
+    let mut best = (distance(src), src);
+    let mut reached = HashSet::from([src]);
+    let mut to_visit = VecDeque::from([src]);
+    while let Some(here) = to_visit.pop_front() {
+      for p in here.neighbors4(map) {
+        if !map.has_clearance(p, clearance) || !map.can_cross(here, p) || !reached.insert(p) {
+          continue;
+        }
+        if distance(p) < best.0 {
+          best = (distance(p), p);
+        }
+        to_visit.push_back(p);
+      }
+    }
+    Some(best.1)
+  }
+
+  // Brings everything up to date after the tiles in a rect become walkable or
+  // unwalkable, for instance when a building goes up or comes down.
+  pub fn update(&mut self, map: &Map, top_left: TilePoint, width: u32, height: u32) {
+    self.flow_fields.clear();
+    for graph in self.graphs.values_mut() {
+      graph.update(map, top_left, width, height);
+    }
+  }
+}
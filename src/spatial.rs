@@ -0,0 +1,177 @@
+use crate::dimensions::{WorldCoord as Coord, WorldPoint as Point, WorldRect as Rect};
+use crate::map::{Map, TILE_WIDTH};
+
+// How many tiles wide each cell of the grid is. Queries look through every
+// cell they touch, so this is a trade-off between looking through a few
+// crowded cells and a lot of nearly empty ones.
+const CELL_TILES: u32 = 2;
+const CELL_WIDTH: Coord = Coord::from_int((CELL_TILES * TILE_WIDTH) as i64);
+
+// Round things on the map, like units, bucketed by which cell of a uniform
+// grid their centers are in, so that finding the ones in some area only means
+// looking through the cells around it. Each thing is known by an item, like
+// its UID, which is what queries give back. Anything off the map goes in the
+// nearest cell.
+pub struct SpatialHash<T> {
+  // In cells.
+  width: i32,
+  height: i32,
+  cells: Vec<Vec<Entry<T>>>,
+  // The biggest radius of anything that's been added, which is as far as
+  // anything can stick out of its cell.
+  max_rad: Coord,
+}
+
+#[derive(Clone, Copy)]
+struct Entry<T> {
+  item: T,
+  pos: Point,
+  rad: Coord,
+}
+
+impl<T: Copy + PartialEq> SpatialHash<T> {
+  // An empty grid covering the map.
+  pub fn new(map: &Map) -> SpatialHash<T> {
+    let cells = |tiles: u32| tiles.div_ceil(CELL_TILES).max(1) as i32;
+    let (width, height) = (cells(map.width), cells(map.height));
+    SpatialHash {
+      width,
+      height,
+      cells: (0..width * height).map(|_| vec![]).collect(),
+      max_rad: Coord::ZERO,
+    }
+  }
+
+  pub fn insert(&mut self, item: T, pos: Point, rad: Coord) {
+    if rad > self.max_rad {
+      self.max_rad = rad;
+    }
+    let cell = self.cell_index(pos);
+    self.cells[cell].push(Entry { item, pos, rad });
+  }
+
+  // Takes out the item, which was last put at pos.
+  pub fn remove(&mut self, item: T, pos: Point) {
+    let cell = self.cell_index(pos);
+    self.cells[cell].retain(|e| e.item != item);
+  }
+
+  // Moves the item, which was last put at from, to to.
+  pub fn update(&mut self, item: T, from: Point, to: Point) {
+    let (old_cell, new_cell) = (self.cell_index(from), self.cell_index(to));
+    let i = match self.cells[old_cell].iter().position(|e| e.item == item) {
+      Some(i) => i,
+      None => return,
+    };
+    if old_cell == new_cell {
+      self.cells[old_cell][i].pos = to;
+    } else {
+      let mut entry = self.cells[old_cell].swap_remove(i);
+      entry.pos = to;
+      self.cells[new_cell].push(entry);
+    }
+  }
+
+  pub fn clear(&mut self) {
+    for cell in self.cells.iter_mut() {
+      cell.clear();
+    }
+    self.max_rad = Coord::ZERO;
+  }
+
+  // Everything whose bounding box overlaps the rect.
+  pub fn in_rect(&self, rect: &Rect) -> Vec<T> {
+    let (left, top) = (rect.top_left.x, rect.top_left.y);
+    let (right, bottom) = (left + rect.width, top + rect.height);
+    self
+      .entries_near(rect.top_left, Point::new(right, bottom))
+      .filter(|e| {
+        left <= e.pos.x + e.rad
+          && e.pos.x - e.rad <= right
+          && top <= e.pos.y + e.rad
+          && e.pos.y - e.rad <= bottom
+      })
+      .map(|e| e.item)
+      .collect()
+  }
+
+  // Everything whose edge is no further than distance from p. With a distance
+  // of zero, that's everything covering p.
+  pub fn within(&self, p: Point, distance: Coord) -> Vec<T> {
+    let reach = Point::new(distance, distance);
+    self
+      .entries_near(p - reach, p + reach)
+      .filter(|e| (e.pos - p).magnitude() - e.rad <= distance)
+      .map(|e| e.item)
+      .collect()
+  }
+
+  // The item closest to p, measuring to its edge, out of those no further than
+  // max_distance away for which pred is true of the item and where it is.
+  // Along with how far away it is.
+  //
+  // Cells are searched in rings around p, working outwards, until the next
+  // ring is too far away to have anything closer in it.
+  pub fn nearest(
+    &self,
+    p: Point,
+    max_distance: Coord,
+    pred: impl Fn(T, Point) -> bool,
+  ) -> Option<(T, Coord)> {
+    let (center_x, center_y) = self.cell_coords(p);
+    let mut best: Option<(T, Coord)> = None;
+    for ring in 0..=self.width.max(self.height) {
+      // Everything in this ring's cells is at least this far from p.
+      let closest = CELL_WIDTH * Coord::from_int((ring - 1).max(0) as i64) - self.max_rad;
+      let beaten = best.is_some_and(|(_, distance)| distance < closest);
+      if closest > max_distance || beaten {
+        break;
+      }
+      for (x, y) in Self::ring_cells(center_x, center_y, ring) {
+        if x < 0 || self.width <= x || y < 0 || self.height <= y {
+          continue;
+        }
+        for e in self.cells[(x + y * self.width) as usize].iter() {
+          let distance = (e.pos - p).magnitude() - e.rad;
+          let closer = best.is_none_or(|(_, best)| distance < best);
+          if distance <= max_distance && closer && pred(e.item, e.pos) {
+            best = Some((e.item, distance));
+          }
+        }
+      }
+    }
+    best
+  }
+
+  // The cells exactly ring cells away from the one at x, y, going across or
+  // down or both.
+  fn ring_cells(x: i32, y: i32, ring: i32) -> Vec<(i32, i32)> {
+    if ring == 0 {
+      return vec![(x, y)];
+    }
+    let across = (x - ring..=x + ring).flat_map(|cx| [(cx, y - ring), (cx, y + ring)]);
+    let down = (y - ring + 1..y + ring).flat_map(|cy| [(x - ring, cy), (x + ring, cy)]);
+    across.chain(down).collect()
+  }
+
+  // The entries in the cells that anything overlapping the rect from
+  // top_left to bottom_right could be in.
+  fn entries_near(&self, top_left: Point, bottom_right: Point) -> impl Iterator<Item = &Entry<T>> {
+    let margin = Point::new(self.max_rad, self.max_rad);
+    let (left, top) = self.cell_coords(top_left - margin);
+    let (right, bottom) = self.cell_coords(bottom_right + margin);
+    (top..=bottom)
+      .flat_map(move |y| (left..=right).map(move |x| (x + y * self.width) as usize))
+      .flat_map(move |i| self.cells[i].iter())
+  }
+
+  fn cell_coords(&self, p: Point) -> (i32, i32) {
+    let cell = |c: Coord, cells: i32| (c / CELL_WIDTH).floor().clamp(0, cells as i64 - 1) as i32;
+    (cell(p.x, self.width), cell(p.y, self.height))
+  }
+
+  fn cell_index(&self, p: Point) -> usize {
+    let (x, y) = self.cell_coords(p);
+    (x + y * self.width) as usize
+  }
+}
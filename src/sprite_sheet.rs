@@ -5,7 +5,6 @@ use sdl2::render::RenderTarget;
 use sdl2::render::Texture;
 use sdl2::render::TextureCreator;
 use sdl2::surface::Surface;
-use sdl2::video::WindowContext;
 
 use std::path::Path;
 use std::str::FromStr;
@@ -16,9 +15,9 @@ pub struct SpriteSheet<'texture> {
 }
 
 impl<'texture> SpriteSheet<'texture> {
-  pub fn from_file<'txc>(
+  pub fn from_file<'txc, T>(
     sprite_map_path: &str,
-    texture_creator: &'txc TextureCreator<WindowContext>,
+    texture_creator: &'txc TextureCreator<T>,
   ) -> Result<SpriteSheet<'txc>, String> {
     let mut sprite_map = vec![];
     let file =
@@ -0,0 +1,757 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use base64::Engine;
+use roxmltree::{Document, Node};
+use serde_json::Value;
+
+use crate::game::{building_types, unit_types};
+use crate::map::{GridTile, Map, TilePoint};
+use crate::map_file::{Location, MapEntity, MapError, MapFile, FORMAT_VERSION, MAX_TILES};
+
+// The tile property that says which kind of terrain a tile is, unless told to
+// use another one.
+pub const DEFAULT_TERRAIN_PROPERTY: &str = "terrain";
+// How much a resource node holds if its object doesn't say.
+const DEFAULT_RESOURCE_AMOUNT: u32 = 500;
+// Tiled keeps whether a tile is flipped or rotated in the top bits of its id.
+const FLIP_FLAGS: u32 = 0xf000_0000;
+
+// Whether the file at path is a map made in Tiled, going by its extension.
+pub fn is_tiled_map(path: &str) -> bool {
+  matches!(
+    Path::new(path).extension().and_then(|e| e.to_str()),
+    Some("tmx" | "tmj" | "json")
+  )
+}
+
+// Reads a map made in the Tiled editor, saved either as a .tmx (XML) or a .tmj
+// (JSON) file. Only orthogonal maps that aren't infinite can be read, with
+// their layers in CSV or uncompressed base64.
+//
+// The terrain comes from the tile layers. Each tile in a tileset that stands
+// for a kind of terrain has terrain_property set to it, either as the tile's
+// character from GridTile::from_char or as its name, like "wall" or
+// "shallow water". Where tile layers overlap, the topmost tile with the
+// property wins, and anywhere without one is open ground.
+//
+// Everything else comes from objects in object layers, going by their class
+// (or type, in older versions of Tiled):
+//
+//   start     a start location. Players are numbered in the order their start
+//             locations are listed, unless they have a "player" property.
+//   unit      a unit of the type the object is named after, for the player in
+//             its "owner" property (0 if it hasn't got one).
+//   building  a building in the same way, with its top-left tile where the
+//             object is.
+//   resource  a resource node, holding its "amount" property.
+//
+// Objects of any other class are left out. The map's name and author come from
+// the map's own "name" and "author" properties.
+pub fn import(path: &str, terrain_property: &str) -> Result<MapFile, MapError> {
+  let text = read(path)?;
+  let tiled = if text.trim_start().starts_with('<') {
+    Tmx {
+      path,
+      doc: &parse_xml(path, &text)?,
+      terrain_property,
+    }
+    .map()?
+  } else {
+    Tmj {
+      path,
+      terrain_property,
+    }
+    .map(&parse_json(path, &text)?)?
+  };
+  tiled.into_map_file(path)
+}
+
+// What's needed from a Tiled map, whichever format it was in.
+struct TiledMap {
+  width: u32,
+  height: u32,
+  tile_width: f64,
+  tile_height: f64,
+  properties: HashMap<String, String>,
+  tilesets: Vec<Tileset>,
+  // From the bottom up.
+  layers: Vec<Layer>,
+  objects: Vec<Object>,
+}
+
+struct Tileset {
+  // The id the tileset's first tile has in the map's layers.
+  first_id: u32,
+  // The terrain each tile stands for, by its id within the tileset.
+  terrain: HashMap<u32, GridTile>,
+}
+
+struct Layer {
+  // Where the layer is in the file, if the format says.
+  loc: Option<Location>,
+  name: String,
+  // The id of the tile in each place, row by row.
+  ids: Vec<u32>,
+}
+
+struct Object {
+  loc: Option<Location>,
+  id: u32,
+  class: String,
+  name: String,
+  // In pixels. For objects that show a tile, y is the bottom of the tile
+  // rather than the top.
+  x: f64,
+  y: f64,
+  height: f64,
+  is_tile: bool,
+  properties: HashMap<String, String>,
+}
+
+impl TiledMap {
+  fn into_map_file(self, path: &str) -> Result<MapFile, MapError> {
+    if self.width == 0 || self.height == 0 {
+      return Err(MapError::Invalid {
+        path: path.to_string(),
+        message: format!("a map can't be {}x{}", self.width, self.height),
+      });
+    }
+    let area = self
+      .width
+      .checked_mul(self.height)
+      .filter(|&area| area as u64 <= MAX_TILES)
+      .ok_or(MapError::Invalid {
+        path: path.to_string(),
+        message: format!(
+          "map is {}x{}, but it can't have more than {} tiles",
+          self.width, self.height, MAX_TILES
+        ),
+      })?;
+    let mut tiles = vec![GridTile::Empty; area as usize];
+    for layer in self.layers.iter() {
+      if layer.ids.len() != tiles.len() {
+        return Err(error(
+          path,
+          layer.loc,
+          format!(
+            "layer {:?} has {} tiles, but the map is {}x{}",
+            layer.name,
+            layer.ids.len(),
+            self.width,
+            self.height
+          ),
+        ));
+      }
+      for (i, id) in layer.ids.iter().enumerate() {
+        let id = id & !FLIP_FLAGS;
+        if id == 0 {
+          continue;
+        }
+        let tileset = self
+          .tilesets
+          .iter()
+          .filter(|t| t.first_id <= id)
+          .max_by_key(|t| t.first_id)
+          .ok_or(error(
+            path,
+            layer.loc,
+            format!(
+              "layer {:?} has tile {}, which isn't in any tileset",
+              layer.name, id
+            ),
+          ))?;
+        if let Some(tile) = tileset.terrain.get(&(id - tileset.first_id)) {
+          tiles[i] = *tile;
+        }
+      }
+    }
+    let map = Map::new(self.width, self.height, tiles);
+
+    let mut start_locations = vec![];
+    let mut entities = vec![];
+    for object in self.objects.iter() {
+      let object_error = |message: String| {
+        error(
+          path,
+          object.loc,
+          format!("object {}: {}", object.id, message),
+        )
+      };
+      let top = if object.is_tile {
+        object.y - object.height
+      } else {
+        object.y
+      };
+      let (x, y) = (
+        (object.x / self.tile_width).floor(),
+        (top / self.tile_height).floor(),
+      );
+      let on_map = 0. <= x && x < self.width as f64 && 0. <= y && y < self.height as f64;
+      let pos = TilePoint::new(x as u32, y as u32);
+      // Start locations aren't entities, so they're None here.
+      let entity = match object.class.to_lowercase().as_str() {
+        "start" => None,
+        "unit" => {
+          if !unit_types().iter().any(|t| t.name == object.name) {
+            return Err(object_error(format!("unknown unit type {:?}", object.name)));
+          }
+          Some(MapEntity::Unit {
+            unit_type: object.name.clone(),
+            owner: object.property("owner", 0).map_err(object_error)?,
+            pos,
+          })
+        }
+        "building" => {
+          if !building_types().iter().any(|t| t.name == object.name) {
+            return Err(object_error(format!(
+              "unknown building type {:?}",
+              object.name
+            )));
+          }
+          Some(MapEntity::Building {
+            building_type: object.name.clone(),
+            owner: object.property("owner", 0).map_err(object_error)?,
+            pos,
+          })
+        }
+        "resource" => Some(MapEntity::ResourceNode {
+          pos,
+          amount: object
+            .property("amount", DEFAULT_RESOURCE_AMOUNT)
+            .map_err(object_error)?,
+        }),
+        _ => continue,
+      };
+      if !on_map {
+        return Err(object_error("it's off the map".to_string()));
+      }
+      match entity {
+        Some(entity) => entities.push((object, entity)),
+        None => {
+          let player = object.property("player", u32::MAX).map_err(object_error)?;
+          start_locations.push((player, pos));
+        }
+      }
+    }
+
+    // Start locations without a player number go after those with one, in
+    // the order they're listed.
+    start_locations.sort_by_key(|(player, _)| *player);
+    for (object, entity) in entities.iter() {
+      if let Some(owner) = entity.owner() {
+        if owner as usize >= start_locations.len() {
+          return Err(error(
+            path,
+            object.loc,
+            format!(
+              "object {}: belongs to player {}, but the map only has {} start locations",
+              object.id,
+              owner,
+              start_locations.len()
+            ),
+          ));
+        }
+      }
+    }
+
+    let file_name = Path::new(path)
+      .file_stem()
+      .and_then(|s| s.to_str())
+      .unwrap_or(path);
+    Ok(MapFile {
+      version: FORMAT_VERSION,
+      name: self
+        .properties
+        .get("name")
+        .cloned()
+        .unwrap_or(file_name.to_string()),
+      author: self.properties.get("author").cloned().unwrap_or_default(),
+      start_locations: start_locations.into_iter().map(|(_, p)| p).collect(),
+      entities: entities
+        .into_iter()
+        .map(|(object, entity)| (object.loc.unwrap_or(Location { line: 0, col: 0 }), entity))
+        .collect(),
+      map,
+    })
+  }
+}
+
+impl Object {
+  // The number in the property with the given name, or default if the object
+  // hasn't got one.
+  fn property<T: FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+    match self.properties.get(name) {
+      Some(value) => value
+        .parse()
+        .map_err(|_| format!("{} should be a whole number, not {:?}", name, value)),
+      None => Ok(default),
+    }
+  }
+}
+
+// The terrain a tile's terrain property stands for: either a tile character or
+// a name, in any case and with spaces or underscores between words.
+fn terrain(value: &str) -> Option<GridTile> {
+  let mut chars = value.chars();
+  if let (Some(c), None) = (chars.next(), chars.next()) {
+    return GridTile::from_char(c);
+  }
+  let name: String = value
+    .chars()
+    .filter(|c| !matches!(c, ' ' | '_' | '-'))
+    .collect();
+  match name.to_lowercase().as_str() {
+    "empty" | "open" => Some(GridTile::Empty),
+    "obstacle" | "wall" => Some(GridTile::Obstacle),
+    "road" => Some(GridTile::Road),
+    "rough" => Some(GridTile::Rough),
+    "shallowwater" => Some(GridTile::ShallowWater),
+    "deepwater" => Some(GridTile::DeepWater),
+    "cliff" => Some(GridTile::Cliff),
+    "ramp" => Some(GridTile::Ramp),
+    _ => None,
+  }
+}
+
+// Reads the terrain of each tile in a tileset from its tiles' properties.
+fn tileset_terrain(
+  path: &str,
+  tiles: impl Iterator<Item = (Option<Location>, u32, HashMap<String, String>)>,
+  terrain_property: &str,
+) -> Result<HashMap<u32, GridTile>, MapError> {
+  let mut terrain_by_id = HashMap::new();
+  for (loc, id, properties) in tiles {
+    if let Some(value) = properties.get(terrain_property) {
+      let tile = terrain(value).ok_or(error(
+        path,
+        loc,
+        format!(
+          "tile {} has {} {:?}, which isn't a kind of terrain",
+          id, terrain_property, value
+        ),
+      ))?;
+      terrain_by_id.insert(id, tile);
+    }
+  }
+  Ok(terrain_by_id)
+}
+
+// Reads a tileset kept in its own file, which is at source relative to the map
+// at path.
+fn external_tileset(
+  path: &str,
+  source: &str,
+  terrain_property: &str,
+) -> Result<HashMap<u32, GridTile>, MapError> {
+  let tileset_path = Path::new(path).with_file_name(source);
+  let tileset_path = tileset_path.to_string_lossy();
+  let text = read(&tileset_path)?;
+  if text.trim_start().starts_with('<') {
+    let doc = parse_xml(&tileset_path, &text)?;
+    Tmx {
+      path: &tileset_path,
+      doc: &doc,
+      terrain_property,
+    }
+    .tileset_terrain(doc.root_element())
+  } else {
+    Tmj {
+      path: &tileset_path,
+      terrain_property,
+    }
+    .tileset_terrain(&parse_json(&tileset_path, &text)?)
+  }
+}
+
+// Reads a map in the XML format.
+struct Tmx<'a, 'input> {
+  path: &'a str,
+  doc: &'a Document<'input>,
+  terrain_property: &'a str,
+}
+
+impl<'a, 'input> Tmx<'a, 'input> {
+  fn map(&self) -> Result<TiledMap, MapError> {
+    let root = self.doc.root_element();
+    if !root.has_tag_name("map") {
+      return Err(self.error(root, "expected a map".to_string()));
+    }
+    let orientation = root.attribute("orientation").unwrap_or("orthogonal");
+    if orientation != "orthogonal" {
+      return Err(self.error(
+        root,
+        format!("only orthogonal maps can be read, not {}", orientation),
+      ));
+    }
+    if root.attribute("infinite") == Some("1") {
+      return Err(self.error(root, "infinite maps can't be read".to_string()));
+    }
+
+    let mut tilesets = vec![];
+    for node in root.children().filter(|n| n.has_tag_name("tileset")) {
+      let first_id = self.attribute(node, "firstgid")?;
+      let terrain = match node.attribute("source") {
+        Some(source) => external_tileset(self.path, source, self.terrain_property)?,
+        None => self.tileset_terrain(node)?,
+      };
+      tilesets.push(Tileset { first_id, terrain });
+    }
+    let mut layers = vec![];
+    let mut objects = vec![];
+    self.layers(root, &mut layers, &mut objects)?;
+
+    Ok(TiledMap {
+      width: self.attribute(root, "width")?,
+      height: self.attribute(root, "height")?,
+      tile_width: self.attribute(root, "tilewidth")?,
+      tile_height: self.attribute(root, "tileheight")?,
+      properties: properties(root),
+      tilesets,
+      layers,
+      objects,
+    })
+  }
+
+  fn tileset_terrain(&self, node: Node) -> Result<HashMap<u32, GridTile>, MapError> {
+    let mut tiles = vec![];
+    for tile in node.children().filter(|n| n.has_tag_name("tile")) {
+      tiles.push((
+        self.loc(tile),
+        self.attribute(tile, "id")?,
+        properties(tile),
+      ));
+    }
+    tileset_terrain(self.path, tiles.into_iter(), self.terrain_property)
+  }
+
+  // Reads the layers under node, going into groups of layers.
+  fn layers(
+    &self,
+    node: Node,
+    layers: &mut Vec<Layer>,
+    objects: &mut Vec<Object>,
+  ) -> Result<(), MapError> {
+    for child in node.children() {
+      match child.tag_name().name() {
+        "layer" => layers.push(Layer {
+          loc: self.loc(child),
+          name: child.attribute("name").unwrap_or_default().to_string(),
+          ids: self.layer_ids(child)?,
+        }),
+        "objectgroup" => {
+          for object in child.children().filter(|n| n.has_tag_name("object")) {
+            objects.push(Object {
+              loc: self.loc(object),
+              id: self.attribute(object, "id")?,
+              class: object
+                .attribute("class")
+                .or(object.attribute("type"))
+                .unwrap_or_default()
+                .to_string(),
+              name: object.attribute("name").unwrap_or_default().to_string(),
+              x: self.attribute(object, "x")?,
+              y: self.attribute(object, "y")?,
+              height: self.optional_attribute(object, "height")?.unwrap_or(0.),
+              is_tile: object.attribute("gid").is_some(),
+              properties: properties(object),
+            });
+          }
+        }
+        "group" => self.layers(child, layers, objects)?,
+        _ => {}
+      }
+    }
+    Ok(())
+  }
+
+  fn layer_ids(&self, layer: Node) -> Result<Vec<u32>, MapError> {
+    let data = match layer.children().find(|n| n.has_tag_name("data")) {
+      Some(data) => data,
+      None => return Ok(vec![]),
+    };
+    if let Some(compression) = data.attribute("compression") {
+      return Err(self.error(
+        data,
+        format!("{} compressed layers can't be read", compression),
+      ));
+    }
+    let text = data.text().unwrap_or_default();
+    match data.attribute("encoding") {
+      Some("csv") => csv_ids(text).ok_or(self.error(data, "bad CSV tile data".to_string())),
+      Some("base64") => {
+        base64_ids(text).ok_or(self.error(data, "bad base64 tile data".to_string()))
+      }
+      Some(encoding) => Err(self.error(data, format!("unknown encoding {:?}", encoding))),
+      // Each tile as its own element.
+      None => data
+        .children()
+        .filter(|n| n.has_tag_name("tile"))
+        .map(|tile| Ok(self.optional_attribute(tile, "gid")?.unwrap_or(0)))
+        .collect(),
+    }
+  }
+
+  fn attribute<T: FromStr>(&self, node: Node, name: &str) -> Result<T, MapError> {
+    self
+      .optional_attribute(node, name)?
+      .ok_or(self.error(node, format!("missing {}", name)))
+  }
+
+  fn optional_attribute<T: FromStr>(&self, node: Node, name: &str) -> Result<Option<T>, MapError> {
+    match node.attribute(name) {
+      Some(value) => value
+        .parse()
+        .map(Some)
+        .map_err(|_| self.error(node, format!("bad {} {:?}", name, value))),
+      None => Ok(None),
+    }
+  }
+
+  fn loc(&self, node: Node) -> Option<Location> {
+    let pos = self.doc.text_pos_at(node.range().start);
+    Some(Location {
+      line: pos.row as usize,
+      col: pos.col as usize,
+    })
+  }
+
+  fn error(&self, node: Node, message: String) -> MapError {
+    error(self.path, self.loc(node), message)
+  }
+}
+
+// The custom properties of a map, tile or object in the XML format.
+fn properties(node: Node) -> HashMap<String, String> {
+  node
+    .children()
+    .filter(|n| n.has_tag_name("properties"))
+    .flat_map(|n| n.children().filter(|n| n.has_tag_name("property")))
+    .filter_map(|property| {
+      let value = property.attribute("value").or(property.text())?;
+      Some((property.attribute("name")?.to_string(), value.to_string()))
+    })
+    .collect()
+}
+
+// Reads a map in the JSON format, which doesn't say where things are in the
+// file, so errors say which layer or object they're about instead.
+struct Tmj<'a> {
+  path: &'a str,
+  terrain_property: &'a str,
+}
+
+impl<'a> Tmj<'a> {
+  fn map(&self, map: &Value) -> Result<TiledMap, MapError> {
+    let orientation = map["orientation"].as_str().unwrap_or("orthogonal");
+    if orientation != "orthogonal" {
+      return Err(self.error(format!(
+        "only orthogonal maps can be read, not {}",
+        orientation
+      )));
+    }
+    if map["infinite"].as_bool() == Some(true) {
+      return Err(self.error("infinite maps can't be read".to_string()));
+    }
+
+    let mut tilesets = vec![];
+    for tileset in self.array(map, "tilesets")? {
+      let first_id = self.number(tileset, "firstgid")?;
+      let terrain = match tileset["source"].as_str() {
+        Some(source) => external_tileset(self.path, source, self.terrain_property)?,
+        None => self.tileset_terrain(tileset)?,
+      };
+      tilesets.push(Tileset { first_id, terrain });
+    }
+    let mut layers = vec![];
+    let mut objects = vec![];
+    self.layers(map, &mut layers, &mut objects)?;
+
+    Ok(TiledMap {
+      width: self.number(map, "width")?,
+      height: self.number(map, "height")?,
+      tile_width: self.number(map, "tilewidth")?,
+      tile_height: self.number(map, "tileheight")?,
+      properties: json_properties(map),
+      tilesets,
+      layers,
+      objects,
+    })
+  }
+
+  fn tileset_terrain(&self, tileset: &Value) -> Result<HashMap<u32, GridTile>, MapError> {
+    let mut tiles = vec![];
+    for tile in tileset["tiles"].as_array().into_iter().flatten() {
+      tiles.push((None, self.number(tile, "id")?, json_properties(tile)));
+    }
+    tileset_terrain(self.path, tiles.into_iter(), self.terrain_property)
+  }
+
+  fn layers(
+    &self,
+    parent: &Value,
+    layers: &mut Vec<Layer>,
+    objects: &mut Vec<Object>,
+  ) -> Result<(), MapError> {
+    for layer in self.array(parent, "layers")? {
+      let name = layer["name"].as_str().unwrap_or_default().to_string();
+      match layer["type"].as_str() {
+        Some("tilelayer") => {
+          let bad_data = || self.error(format!("layer {:?} has bad tile data", name));
+          let ids = match (&layer["data"], layer["encoding"].as_str()) {
+            (Value::Array(ids), _) => ids
+              .iter()
+              .map(|id| id.as_u64().map(|id| id as u32))
+              .collect::<Option<_>>()
+              .ok_or_else(bad_data)?,
+            (Value::String(data), Some("base64")) => {
+              if let Some(compression) = layer["compression"].as_str().filter(|c| !c.is_empty()) {
+                return Err(self.error(format!(
+                  "layer {:?} is {} compressed, which can't be read",
+                  name, compression
+                )));
+              }
+              base64_ids(data).ok_or_else(bad_data)?
+            }
+            (Value::Null, _) if layer["chunks"].is_array() => {
+              return Err(self.error("infinite maps can't be read".to_string()));
+            }
+            _ => return Err(bad_data()),
+          };
+          layers.push(Layer {
+            loc: None,
+            name,
+            ids,
+          });
+        }
+        Some("objectgroup") => {
+          for object in layer["objects"].as_array().into_iter().flatten() {
+            let id = self.number(object, "id")?;
+            let number = |key: &str| {
+              object[key]
+                .as_f64()
+                .ok_or(self.error(format!("object {}: missing {}", id, key)))
+            };
+            objects.push(Object {
+              loc: None,
+              id,
+              class: object["class"]
+                .as_str()
+                .filter(|c| !c.is_empty())
+                .or(object["type"].as_str())
+                .unwrap_or_default()
+                .to_string(),
+              name: object["name"].as_str().unwrap_or_default().to_string(),
+              x: number("x")?,
+              y: number("y")?,
+              height: object["height"].as_f64().unwrap_or(0.),
+              is_tile: object.get("gid").is_some(),
+              properties: json_properties(object),
+            });
+          }
+        }
+        Some("group") => self.layers(layer, layers, objects)?,
+        _ => {}
+      }
+    }
+    Ok(())
+  }
+
+  fn array<'v>(&self, value: &'v Value, key: &str) -> Result<&'v Vec<Value>, MapError> {
+    value[key]
+      .as_array()
+      .ok_or(self.error(format!("missing {}", key)))
+  }
+
+  fn number<T: FromStr>(&self, value: &Value, key: &str) -> Result<T, MapError> {
+    match &value[key] {
+      Value::Number(n) => n
+        .to_string()
+        .parse()
+        .map_err(|_| self.error(format!("bad {} {}", key, n))),
+      _ => Err(self.error(format!("missing {}", key))),
+    }
+  }
+
+  fn error(&self, message: String) -> MapError {
+    error(self.path, None, message)
+  }
+}
+
+// The custom properties of a map, tile or object in the JSON format, with the
+// values written out the way they would be in the XML format.
+fn json_properties(value: &Value) -> HashMap<String, String> {
+  value["properties"]
+    .as_array()
+    .into_iter()
+    .flatten()
+    .filter_map(|property| {
+      let value = match &property["value"] {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+      };
+      Some((property["name"].as_str()?.to_string(), value))
+    })
+    .collect()
+}
+
+fn csv_ids(text: &str) -> Option<Vec<u32>> {
+  text.split(',').map(|id| id.trim().parse().ok()).collect()
+}
+
+// Base64 tile data is each tile's id as 4 little-endian bytes.
+fn base64_ids(text: &str) -> Option<Vec<u32>> {
+  let bytes = base64::engine::general_purpose::STANDARD
+    .decode(text.trim())
+    .ok()?;
+  if bytes.len() % 4 != 0 {
+    return None;
+  }
+  Some(
+    bytes
+      .chunks(4)
+      .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+      .collect(),
+  )
+}
+
+fn read(path: &str) -> Result<String, MapError> {
+  std::fs::read_to_string(path).map_err(|error| MapError::Io {
+    path: path.to_string(),
+    error,
+  })
+}
+
+fn parse_xml<'input>(path: &str, text: &'input str) -> Result<Document<'input>, MapError> {
+  Document::parse(text).map_err(|e| {
+    let pos = e.pos();
+    syntax_error(path, pos.row as usize, pos.col as usize, e.to_string())
+  })
+}
+
+fn parse_json(path: &str, text: &str) -> Result<Value, MapError> {
+  serde_json::from_str(text).map_err(|e| syntax_error(path, e.line(), e.column(), e.to_string()))
+}
+
+// The XML and JSON parsers put where the error is at the end of their
+// messages, which would say it twice.
+fn syntax_error(path: &str, line: usize, col: usize, message: String) -> MapError {
+  let message = match message.rsplit_once(" at ") {
+    Some((message, _)) => message.to_string(),
+    None => message,
+  };
+  MapError::at(path, Location { line, col }, message)
+}
+
+// An error about something at loc in the file, or about the file as a whole
+// if there's no telling where.
+fn error(path: &str, loc: Option<Location>, message: String) -> MapError {
+  match loc {
+    Some(loc) => MapError::at(path, loc, message),
+    None => MapError::Invalid {
+      path: path.to_string(),
+      message,
+    },
+  }
+}
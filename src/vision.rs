@@ -0,0 +1,63 @@
+use crate::map::{Map, TilePoint};
+
+// What a player knows about a tile.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Visibility {
+  // Never seen.
+  Unexplored,
+  // Seen before, but not right now. The terrain is known, but not what's on
+  // it.
+  Explored,
+  // In sight of something the player owns.
+  Visible,
+}
+
+// What one player can see of the map, tile by tile.
+pub struct Vision {
+  width: u32,
+  height: u32,
+  tiles: Vec<Visibility>,
+}
+
+impl Vision {
+  // Vision of a map that hasn't been explored at all.
+  pub fn new(map: &Map) -> Vision {
+    Vision {
+      width: map.width,
+      height: map.height,
+      tiles: vec![Visibility::Unexplored; (map.width * map.height) as usize],
+    }
+  }
+
+  pub fn get(&self, p: TilePoint) -> Visibility {
+    if p.x() >= self.width || p.y() >= self.height {
+      return Visibility::Unexplored;
+    }
+    self.tiles[(p.x() + p.y() * self.width) as usize]
+  }
+
+  pub fn is_visible(&self, p: TilePoint) -> bool {
+    self.get(p) == Visibility::Visible
+  }
+
+  // Stops seeing everything, remembering what was visible as explored. Vision
+  // is worked out afresh each tick by fading it, then revealing what's in
+  // sight of everything the player owns.
+  pub fn fade(&mut self) {
+    for tile in self.tiles.iter_mut() {
+      if *tile == Visibility::Visible {
+        *tile = Visibility::Explored;
+      }
+    }
+  }
+
+  // Makes everything in sight of something on the tile from visible, out to
+  // range tiles away. Nothing can see up to higher ground, or past walls.
+  pub fn reveal(&mut self, map: &Map, from: TilePoint, range: u32) {
+    for p in map.visible_tiles(from, range) {
+      if p.x() < self.width && p.y() < self.height {
+        self.tiles[(p.x() + p.y() * self.width) as usize] = Visibility::Visible;
+      }
+    }
+  }
+}